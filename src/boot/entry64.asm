@@ -26,10 +26,20 @@ _start:
 
     # 我们在虚拟内存空间中：随意跳转到虚拟地址！
     # 跳转到 rust_main
+    # a0 (hartid) 与 a1 (设备树的物理地址) 未被修改，原样作为参数传给 rust_main
     lui t0, %hi(rust_main)
     addi t0, t0, %lo(rust_main)
     jr t0
 
+    .section .bss.stack
+    .align 12
+    .global bootstack
+bootstack:
+    # 启动栈大小为 16KiB
+    .space 4096 * 4
+    .global bootstacktop
+bootstacktop:
+
     .section .data
     # 由于我们要把这个页表放到一个页里面，因此必须 12 位对齐
     .align 12
@@ -3,11 +3,34 @@ pub const PHYSICAL_MEMORY_END: usize = 0x88000000;
 pub const KERNEL_BEGIN_PADDR: usize = 0x80200000;
 pub const KERNEL_BEGIN_VADDR: usize = 0xffffffffc0200000;
 
-pub const MAX_PHYSICAL_MEMORY: usize = 0x8000000;
-pub const MAX_PHYSICAL_PAGES: usize = MAX_PHYSICAL_MEMORY >> 12;
-
 pub const KERNEL_HEAP_SIZE: usize = 0x800000;
+pub const KERNEL_HEAP_GROW_SIZE: usize = 0x100000;
 
 pub const PHYSICAL_MEMORY_OFFSET: usize = 0xffffffff40000000;
+// 物理地址加上 PHYSICAL_MEMORY_OFFSET 后不能溢出，线性映射窗口只能覆盖到这里
+pub const PHYSICAL_MEMORY_LIMIT: usize = 0usize.wrapping_sub(PHYSICAL_MEMORY_OFFSET);
+
+pub const PAGE_SIZE: usize = 4096;
+// map_anonymous 等接口在地址空间中分配虚拟地址的范围，位于用户态地址空间内
+pub const MMAP_BASE: usize = 0x1000_0000;
+pub const MMAP_END: usize = 0x20_0000_0000;
+
+// 所有地址空间用同一张页表映射这 1 GiB，内核栈与 ioremap 窗口都在其中，
+// 在内核地址空间中映射的页在其他地址空间中同样可见；须按 1 GiB 对齐，只能经由 KERNEL_MEMORY_SET 映射
+pub const KERNEL_SHARED_BASE: usize = 0xffff_ffff_0000_0000;
+pub const KERNEL_SHARED_END: usize = PHYSICAL_MEMORY_OFFSET;
+
+// ioremap 映射设备寄存器所用的内核虚拟地址范围，紧挨在物理内存线性映射窗口之下
+pub const IOREMAP_BASE: usize = 0xffff_ffff_2000_0000;
+pub const IOREMAP_END: usize = PHYSICAL_MEMORY_OFFSET;
+
+// 批量修改页表时，超过这么多页就不再逐页刷新 TLB，而是刷新整个地址空间
+pub const TLB_FLUSH_BATCH: usize = 32;
 
-pub const PAGE_SIZE: usize = 4096;
\ No newline at end of file
+// 内核栈所在的内核虚拟地址范围，紧挨在 ioremap 窗口之下，大小须为 2 的幂
+pub const KERNEL_STACK_BASE: usize = 0xffff_ffff_1000_0000;
+pub const KERNEL_STACK_END: usize = IOREMAP_BASE;
+// 该范围被划分为若干个槽，每个槽最低的一页是不映射的保护页，其余部分是一个内核栈
+// 槽的大小须为 2 的幂，以便陷入时用移位判断 sp 是否已经落入保护页
+pub const KERNEL_STACK_SLOT_SIZE: usize = 0x10000;
+pub const KERNEL_STACK_SIZE: usize = KERNEL_STACK_SLOT_SIZE - PAGE_SIZE;
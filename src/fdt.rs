@@ -0,0 +1,188 @@
+//! Just enough of a flattened device tree parser to find out where RAM is and which paging
+//! modes the MMU supports.
+//!
+//! OpenSBI hands the physical address of the device tree blob to the kernel in `a1`.
+//! Only `/memory*` nodes, the `/reserved-memory` children, the memory reservation block and
+//! the `mmu-type` of the first `/cpus/cpu*` node are looked at.
+
+use crate::memory::layout::MemoryLayout;
+use core::ffi::{c_char, CStr};
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+const MAX_DEPTH: usize = 16;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Node {
+    Other,
+    Memory,
+    ReservedMemory,
+    Reserved,
+}
+
+struct Blob {
+    base: usize,
+}
+
+impl Blob {
+    fn u32_at(&self, offset: usize) -> u32 {
+        u32::from_be(unsafe { core::ptr::read_unaligned((self.base + offset) as *const u32) })
+    }
+
+    fn u64_at(&self, offset: usize) -> u64 {
+        u64::from_be(unsafe { core::ptr::read_unaligned((self.base + offset) as *const u64) })
+    }
+
+    fn str_at(&self, offset: usize) -> &[u8] {
+        unsafe { CStr::from_ptr((self.base + offset) as *const c_char) }.to_bytes()
+    }
+
+    /// Reads a number made of `cells` big-endian 32-bit cells.
+    fn cells_at(&self, offset: usize, cells: usize) -> usize {
+        (0..cells).fold(0, |acc, i| (acc << 32) | self.u32_at(offset + i * 4) as usize)
+    }
+}
+
+fn align4(x: usize) -> usize {
+    (x + 3) & !3
+}
+
+/// A token of the structure block. Property values are left in the blob, at `value`.
+enum Token<'a> {
+    BeginNode(&'a [u8]),
+    EndNode,
+    Prop { name: &'a [u8], value: usize, len: usize },
+}
+
+/// The tokens of the structure block, `FDT_NOP`s skipped.
+fn tokens(blob: &Blob) -> impl Iterator<Item = Token<'_>> {
+    let off_dt_strings = blob.u32_at(12) as usize;
+    let mut offset = blob.u32_at(8) as usize;
+    core::iter::from_fn(move || loop {
+        let token = blob.u32_at(offset);
+        offset += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                let name = blob.str_at(offset);
+                offset = align4(offset + name.len() + 1);
+                return Some(Token::BeginNode(name));
+            }
+            FDT_END_NODE => return Some(Token::EndNode),
+            FDT_PROP => {
+                let len = blob.u32_at(offset) as usize;
+                let name = blob.str_at(off_dt_strings + blob.u32_at(offset + 4) as usize);
+                let value = offset + 8;
+                offset = align4(value + len);
+                return Some(Token::Prop { name, value, len });
+            }
+            FDT_NOP => {}
+            FDT_END => return None,
+            _ => panic!("bad device tree token {:#x} at {:#x}", token, offset - 4),
+        }
+    })
+}
+
+/// Size in bytes of the device tree blob at virtual address `va`, or `None` if there is no
+/// valid blob there.
+pub fn total_size(va: usize) -> Option<usize> {
+    let blob = Blob { base: va };
+    if blob.u32_at(0) != FDT_MAGIC {
+        return None;
+    }
+    Some(blob.u32_at(4) as usize)
+}
+
+/// Collects the RAM and reserved ranges described by the device tree blob at virtual
+/// address `va` into `layout`. Returns `false` if `va` does not point to a device tree.
+pub fn parse_memory_layout(va: usize, layout: &mut MemoryLayout) -> bool {
+    if total_size(va).is_none() {
+        return false;
+    }
+    let blob = Blob { base: va };
+    let mut offset = blob.u32_at(16) as usize;
+    loop {
+        let (address, size) = (blob.u64_at(offset) as usize, blob.u64_at(offset + 8) as usize);
+        if address == 0 && size == 0 {
+            break;
+        }
+        layout.add_reserved(address, address + size);
+        offset += 16;
+    }
+
+    // `#address-cells`/`#size-cells` declared by the node at each depth, applying to its children.
+    let mut address_cells = [2; MAX_DEPTH];
+    let mut size_cells = [1; MAX_DEPTH];
+    let mut nodes = [Node::Other; MAX_DEPTH];
+    let mut depth = 0;
+    for token in tokens(&blob) {
+        match token {
+            Token::BeginNode(name) => {
+                depth += 1;
+                assert!(depth < MAX_DEPTH, "device tree is too deep!");
+                address_cells[depth] = 2;
+                size_cells[depth] = 1;
+                nodes[depth] = match (depth, nodes[depth - 1]) {
+                    (2, _) if name == b"memory" || name.starts_with(b"memory@") => Node::Memory,
+                    (2, _) if name == b"reserved-memory" => Node::ReservedMemory,
+                    (3, Node::ReservedMemory) => Node::Reserved,
+                    _ => Node::Other,
+                };
+            }
+            Token::EndNode => {
+                depth -= 1;
+            }
+            Token::Prop { name, value, len } => match name {
+                b"#address-cells" => address_cells[depth] = blob.u32_at(value) as usize,
+                b"#size-cells" => size_cells[depth] = blob.u32_at(value) as usize,
+                b"reg" if nodes[depth] == Node::Memory || nodes[depth] == Node::Reserved => {
+                    let (ac, sc) = (address_cells[depth - 1], size_cells[depth - 1]);
+                    let entry = (ac + sc) * 4;
+                    for reg in (value..value + len / entry * entry).step_by(entry) {
+                        let start = blob.cells_at(reg, ac);
+                        let end = start + blob.cells_at(reg + ac * 4, sc);
+                        if nodes[depth] == Node::Memory {
+                            layout.add_memory(start, end);
+                        } else {
+                            layout.add_reserved(start, end);
+                        }
+                    }
+                }
+                _ => {}
+            },
+        }
+    }
+    true
+}
+
+/// The `mmu-type` of the first CPU in the device tree blob at virtual address `va`, such as
+/// `riscv,sv48`.
+pub fn mmu_type(va: usize) -> Option<&'static [u8]> {
+    total_size(va)?;
+    let blob = Blob { base: va };
+    let mut path: [&[u8]; MAX_DEPTH] = [&[]; MAX_DEPTH];
+    let mut depth = 0;
+    for token in tokens(&blob) {
+        match token {
+            Token::BeginNode(name) => {
+                depth += 1;
+                assert!(depth < MAX_DEPTH, "device tree is too deep!");
+                path[depth] = name;
+            }
+            Token::EndNode => depth -= 1,
+            Token::Prop { name: b"mmu-type", value, len } if depth == 3 && path[2] == b"cpus" => {
+                if path[3] == b"cpu" || path[3].starts_with(b"cpu@") {
+                    let value = unsafe { core::slice::from_raw_parts((blob.base + value) as *const u8, len) };
+                    return Some(value.strip_suffix(b"\0").unwrap_or(value));
+                }
+            }
+            Token::Prop { .. } => {}
+        }
+    }
+    None
+}
@@ -3,32 +3,82 @@ global_asm!(include_str!("boot/entry64.asm"));
 use core::arch::global_asm;
 
 use crate::consts::*;
+use crate::memory::kernel_stack::KernelStack;
+use crate::memory::layout::MemoryLayout;
+use crate::paging::{access_pa_via_va, set_paging_mode, PagingMode};
+
 #[no_mangle]
-pub extern "C" fn rust_main() -> ! {
-    extern "C" {
-        fn end();
+pub extern "C" fn rust_main(_hartid: usize, dtb_pa: usize) -> ! {
+    let layout = discover_memory(dtb_pa);
+    for region in layout.memory() {
+        println!("physical memory {:?}", region);
     }
-    println!(
-        "free physical memory paddr = [{:#x}, {:#x})",
-        end as usize - KERNEL_BEGIN_VADDR + KERNEL_BEGIN_PADDR,
-        PHYSICAL_MEMORY_END
-    );
-	println!(
-        "free physical memory ppn = [{:#x}, {:#x})",
-        ((end as usize - KERNEL_BEGIN_VADDR + KERNEL_BEGIN_PADDR) >> 12) + 1,
-        PHYSICAL_MEMORY_END >> 12
-	);
-    // crate::interrupt::init();
+    for region in layout.reserved() {
+        println!("reserved memory {:?}", region);
+    }
+    choose_paging_mode(dtb_pa);
+    crate::interrupt::init();
     // crate::timer::init();
 
-	crate::memory::init(
-        ((end as usize - KERNEL_BEGIN_VADDR + KERNEL_BEGIN_PADDR) >> 12) + 1,
-        PHYSICAL_MEMORY_END >> 12
-    );
+	crate::memory::init(&layout);
+	// 启动栈下方没有保护页，内存管理就绪后就换到带保护页的内核栈上
+	KernelStack::new()
+		.expect("failed to allocate the boot kernel stack")
+		.run(kernel_main)
+}
+
+/// The rest of booting, on a kernel stack with a guard page.
+extern "C" fn kernel_main() -> ! {
+	kernel_stack_test();
 	dynamic_allocating_test();
+	heap_growing_test();
+	slab_cache_test();
+	demand_paging_test();
+	copy_on_write_test();
+	swap_test();
+	mmap_test();
+	user_access_test();
+	ioremap_test();
+	range_mapping_test();
+    print!("{}", crate::memory::stats());
     loop {}
 }
 
+/// Reads the RAM layout from the device tree OpenSBI left at `dtb_pa`, and reserves the
+/// firmware, the kernel image and the device tree itself.
+fn discover_memory(dtb_pa: usize) -> MemoryLayout {
+    extern "C" {
+        fn end();
+    }
+    let mut layout = MemoryLayout::new();
+    let dtb_va = access_pa_via_va(dtb_pa);
+    if dtb_pa != 0 && crate::fdt::parse_memory_layout(dtb_va, &mut layout) {
+        let dtb_size = crate::fdt::total_size(dtb_va).unwrap();
+        layout.add_reserved(dtb_pa, dtb_pa + dtb_size);
+    } else {
+        println!("no device tree found, assuming memory ends at {:#x}", PHYSICAL_MEMORY_END);
+        layout.add_memory(KERNEL_BEGIN_PADDR, PHYSICAL_MEMORY_END);
+    }
+    let kernel_end = end as *const () as usize - KERNEL_BEGIN_VADDR + KERNEL_BEGIN_PADDR;
+    layout.add_reserved(layout.memory()[0].start, kernel_end);
+    layout
+}
+
+/// Uses Sv48 for every page table built from now on if the device tree says the MMU
+/// supports it, Sv39 otherwise.
+fn choose_paging_mode(dtb_pa: usize) {
+    let mmu_type = match dtb_pa {
+        0 => None,
+        _ => crate::fdt::mmu_type(access_pa_via_va(dtb_pa)),
+    };
+    // Sv57 implies Sv48 support.
+    let mode = match mmu_type {
+        Some(b"riscv,sv48") | Some(b"riscv,sv57") => PagingMode::Sv48,
+        _ => PagingMode::Sv39,
+    };
+    set_paging_mode(mode);
+    println!("paging mode: {:?}", mode);
+}
 
 fn dynamic_allocating_test() {
 	use alloc::vec::Vec;
@@ -62,3 +112,429 @@ fn dynamic_allocating_test() {
 	assert!(vec_addr >= lbss && vec_addr < rbss);
 	println!("vec is in section .bss!");
 }
+
+fn heap_growing_test() {
+	use alloc::vec;
+
+	extern "C" {
+		fn ebss();
+	}
+	// 超过初始堆大小的分配只能由新分配的物理页满足
+	let big = vec![1u8; KERNEL_HEAP_SIZE * 2];
+	assert!(big.iter().all(|&b| b == 1));
+	assert!(big.as_ptr() as usize >= ebss as *const () as usize);
+	println!("heap grew beyond its initial {:#x} bytes!", KERNEL_HEAP_SIZE);
+}
+
+fn slab_cache_test() {
+	use crate::memory::slab::ObjectCache;
+
+	struct Node {
+		value: usize,
+		next: usize,
+	}
+	let cache = ObjectCache::<Node>::new("test_node").unwrap();
+	let mut nodes = [None; 100];
+	for (i, node) in nodes.iter_mut().enumerate() {
+		*node = cache.alloc(Node { value: i, next: i + 1 });
+	}
+	for (i, node) in nodes.iter().enumerate() {
+		let node = unsafe { node.unwrap().as_ref() };
+		assert!(node.value == i && node.next == i + 1);
+	}
+	for node in nodes.iter() {
+		unsafe { cache.free(node.unwrap()) };
+	}
+	println!("slab cache assertion successfully!");
+}
+
+fn demand_paging_test() {
+	use crate::memory::memory_set::attr::MemoryAttr;
+	use crate::memory::memory_set::handler::Delay;
+	use crate::memory::memory_set::{PushError, KERNEL_MEMORY_SET};
+
+	// 内核地址空间中未被使用的一段虚拟地址
+	const START: usize = 0xffff_fffe_0000_0000;
+	const PAGES: usize = 4;
+	let end = START + PAGES * PAGE_SIZE;
+	let free = || crate::memory::stats().free_frames;
+
+	KERNEL_MEMORY_SET
+		.lock()
+		.as_mut()
+		.unwrap()
+		.push(START, end, MemoryAttr::new(), Delay::new())
+		.unwrap();
+	// 与已有区域重叠的区域不能加入
+	let overlap = KERNEL_MEMORY_SET
+		.lock()
+		.as_mut()
+		.unwrap()
+		.push(end - PAGE_SIZE, end + PAGE_SIZE, MemoryAttr::new(), Delay::new());
+	assert!(matches!(overlap, Err(PushError::Overlap { .. })));
+	let before = free();
+	let page = |i: usize| (START + i * PAGE_SIZE) as *mut usize;
+	for i in 0..PAGES {
+		unsafe {
+			assert!(page(i).read_volatile() == 0);
+			page(i).write_volatile(i);
+		}
+	}
+	for i in 0..PAGES {
+		assert!(unsafe { page(i).read_volatile() } == i);
+	}
+	// 页表本身也可能占用新的物理页
+	assert!(before - free() >= PAGES);
+	println!("demand paging mapped {} pages on first touch!", PAGES);
+
+	let touched = free();
+	KERNEL_MEMORY_SET.lock().as_mut().unwrap().remove(START, end).unwrap();
+	assert!(free() >= touched + PAGES);
+	println!("demand paging freed its frames!");
+}
+
+fn copy_on_write_test() {
+	use crate::memory::memory_set::attr::MemoryAttr;
+	use crate::memory::memory_set::handler::ByFrame;
+	use crate::memory::memory_set::{switch_to, KERNEL_MEMORY_SET};
+	use crate::utils::mutex::Mutex;
+	use alloc::sync::Arc;
+
+	const START: usize = 0xffff_fffe_0000_0000;
+	const PAGES: usize = 4;
+	let end = START + PAGES * PAGE_SIZE;
+	let free = || crate::memory::stats().free_frames;
+	let page = |i: usize| (START + i * PAGE_SIZE) as *mut usize;
+
+	let child = {
+		let mut kernel = KERNEL_MEMORY_SET.lock();
+		let kernel = kernel.as_mut().unwrap();
+		kernel.push(START, end, MemoryAttr::new(), ByFrame::new()).unwrap();
+		for i in 0..PAGES {
+			unsafe { page(i).write_volatile(i) };
+		}
+		Arc::new(Mutex::new(kernel.clone_cow().unwrap()))
+	};
+
+	// 第一次写入共享页时才复制
+	let before = free();
+	unsafe { page(0).write_volatile(100) };
+	assert!(before - free() == 1);
+	unsafe { switch_to(Some(child.clone())) };
+	let seen = unsafe { page(0).read_volatile() };
+	unsafe { switch_to(None) };
+	assert!(seen == 0);
+	println!("copy on write kept the pages of the clone apart!");
+
+	// 子地址空间释放后，剩下的页不再被共享，写入时无需复制
+	drop(child);
+	let before = free();
+	unsafe { page(1).write_volatile(101) };
+	assert!(free() == before);
+	assert!(unsafe { page(0).read_volatile() } == 100);
+
+	KERNEL_MEMORY_SET.lock().as_mut().unwrap().remove(START, end).unwrap();
+	println!("copy on write freed its frames!");
+}
+
+fn swap_test() {
+	use crate::memory::memory_set::attr::MemoryAttr;
+	use crate::memory::memory_set::handler::Swappable;
+	use crate::memory::memory_set::KERNEL_MEMORY_SET;
+	use crate::memory::swap::block_device::{RamDisk, BLOCK_SIZE};
+	use crate::memory::swap::policy::{Clock, EnhancedClock, Fifo};
+	use crate::memory::swap::{self, SwapManager};
+
+	const START: usize = 0xffff_fffe_0000_0000;
+	const PAGES: usize = 8;
+	// 最多只让 4 页常驻内存，其余的页必须换出
+	const RESIDENT: usize = 4;
+	let end = START + PAGES * PAGE_SIZE;
+	let page = |i: usize| (START + i * PAGE_SIZE) as *mut usize;
+	let disk = || RamDisk::new(PAGES * PAGE_SIZE / BLOCK_SIZE);
+
+	for manager in [
+		SwapManager::new(disk(), Fifo::new(), RESIDENT),
+		SwapManager::new(disk(), Clock::new(), RESIDENT),
+		SwapManager::new(disk(), EnhancedClock::new(), RESIDENT),
+	] {
+		swap::init(manager);
+		KERNEL_MEMORY_SET
+			.lock()
+			.as_mut()
+			.unwrap()
+			.push(START, end, MemoryAttr::new(), Swappable::new())
+			.unwrap();
+		for round in 0..3 {
+			for i in 0..PAGES {
+				unsafe {
+					assert!(page(i).read_volatile() == round * i);
+					page(i).write_volatile((round + 1) * i);
+				}
+			}
+		}
+		let stats = swap::stats().unwrap();
+		assert!(stats.resident == RESIDENT && stats.used_slots == PAGES - RESIDENT);
+		assert!(stats.page_outs > 0 && stats.page_ins > 0);
+		println!("{}", stats);
+
+		KERNEL_MEMORY_SET.lock().as_mut().unwrap().remove(START, end).unwrap();
+		let stats = swap::stats().unwrap();
+		assert!(stats.resident == 0 && stats.used_slots == 0);
+
+		// 只读访问的页，无论是新映射的还是换入的，都应当是干净的
+		let state = || {
+			let mut guard = KERNEL_MEMORY_SET.lock();
+			let entry = guard.as_mut().unwrap().get_entry(START).unwrap();
+			(entry.swapped(), entry.dirty())
+		};
+		KERNEL_MEMORY_SET
+			.lock()
+			.as_mut()
+			.unwrap()
+			.push(START, START + PAGE_SIZE, MemoryAttr::new(), Swappable::new())
+			.unwrap();
+		assert!(unsafe { page(0).read_volatile() } == 0);
+		assert!(state() == (false, false));
+		assert!(swap::swap_out() && state().0);
+		assert!(unsafe { page(0).read_volatile() } == 0);
+		assert!(state() == (false, false));
+		KERNEL_MEMORY_SET.lock().as_mut().unwrap().remove(START, START + PAGE_SIZE).unwrap();
+	}
+	println!("swapping kept every page!");
+}
+
+fn mmap_test() {
+	use crate::memory::memory_set::mmap::{MapFlags, MmapError, Prot, SharedMemory};
+	use crate::memory::memory_set::{switch_to, MemorySet, KERNEL_MEMORY_SET};
+	use crate::register::sstatus;
+	use crate::utils::mutex::Mutex;
+	use alloc::sync::Arc;
+
+	let rw = Prot::READ | Prot::WRITE;
+	// 映射出的都是用户页，内核需要打开 SUM 才能访问
+	unsafe { sstatus::set_sum() };
+	let mut guard = KERNEL_MEMORY_SET.lock();
+	let kernel = guard.as_mut().unwrap();
+
+	// 持有锁时缺页无法处理，所以要访问的页都预先映射好
+	let a = kernel.map_anonymous(3 * PAGE_SIZE, rw, MapFlags::POPULATE).unwrap();
+	let b = kernel.map_anonymous(PAGE_SIZE, rw, MapFlags::empty()).unwrap();
+	assert!(b == a + 3 * PAGE_SIZE);
+	for i in 0..3 {
+		unsafe { ((a + i * PAGE_SIZE) as *mut usize).write_volatile(i) };
+	}
+	assert!(kernel.mprotect(a + PAGE_SIZE, PAGE_SIZE, Prot::READ) == Ok(()));
+	assert!(kernel.mprotect(a, PAGE_SIZE, Prot::all()) == Err(MmapError::PermissionDenied));
+	assert!(kernel.munmap(a + PAGE_SIZE, PAGE_SIZE) == Ok(()));
+	assert!(kernel.mprotect(a, 3 * PAGE_SIZE, Prot::READ) == Err(MmapError::NotMapped));
+	assert!(kernel.munmap(a + 1, PAGE_SIZE) == Err(MmapError::InvalidArgument));
+	// 最先适配：空出来的那一页会被下一次映射用上
+	let c = kernel.map_anonymous(PAGE_SIZE, Prot::READ, MapFlags::empty()).unwrap();
+	assert!(c == a + PAGE_SIZE);
+	assert!(unsafe { ((a + 2 * PAGE_SIZE) as *const usize).read_volatile() } == 2);
+	println!("mmap, munmap and mprotect work!");
+
+	let memory = SharedMemory::new(PAGE_SIZE).unwrap();
+	let s = kernel.map_shared(&memory, rw).unwrap();
+	unsafe { (s as *mut usize).write_volatile(0x5a5a) };
+	drop(guard);
+	let other = Arc::new(Mutex::new(MemorySet::new()));
+	let t = other.lock().map_shared(&memory, Prot::READ).unwrap();
+	unsafe { switch_to(Some(other.clone())) };
+	let seen = unsafe { (t as *const usize).read_volatile() };
+	unsafe { switch_to(None) };
+	assert!(seen == 0x5a5a);
+	drop(other);
+	let mut guard = KERNEL_MEMORY_SET.lock();
+	let kernel = guard.as_mut().unwrap();
+	assert!(kernel.munmap(MMAP_BASE, MMAP_END - MMAP_BASE) == Ok(()));
+	assert!(kernel.find_free_area(PAGE_SIZE) == Some(MMAP_BASE));
+	unsafe { sstatus::clear_sum() };
+	println!("shared memory is seen by every address space!");
+}
+
+fn user_access_test() {
+	use crate::memory::memory_set::mmap::{MapFlags, Prot};
+	use crate::memory::memory_set::KERNEL_MEMORY_SET;
+	use crate::register::sstatus;
+	use crate::user::*;
+
+	let map = |prot| {
+		KERNEL_MEMORY_SET
+			.lock()
+			.as_mut()
+			.unwrap()
+			.map_anonymous(2 * PAGE_SIZE, prot, MapFlags::empty())
+			.unwrap()
+	};
+	// 惰性映射的页在复制过程中缺页，由缺页处理程序补上
+	let buf = map(Prot::READ | Prot::WRITE);
+	let readonly = map(Prot::READ);
+
+	// 跨越两页
+	let text = buf + PAGE_SIZE - 3;
+	assert!(copy_to_user(text, b"hello\0") == Ok(()));
+	let mut hello = [0u8; 5];
+	assert!(copy_from_user(&mut hello, text) == Ok(()));
+	assert!(&hello == b"hello");
+	let mut name = [0xffu8; 16];
+	assert!(strncpy_from_user(&mut name, text) == Ok(5));
+	assert!(&name[..6] == b"hello\0");
+	assert!(strncpy_from_user(&mut name[..3], text) == Ok(3));
+
+	let ptr = UserPtr::<u64>::new(buf + 8);
+	assert!(ptr.write(0x1234_5678_9abc_def0) == Ok(()));
+	assert!(ptr.read() == Ok(0x1234_5678_9abc_def0));
+	assert!(UserPtr::<[u8; 5]>::new(text).read() == Ok(*b"hello"));
+	let slice = UserSlice::new(buf, 16);
+	assert!(slice.len() == 16 && !slice.is_empty());
+	assert!(slice.read().unwrap()[8..] == 0x1234_5678_9abc_def0u64.to_ne_bytes());
+	assert!(slice.write(&[0; 17]) == Err(Efault));
+
+	// 内核地址、只读页和映射之外的地址都不能访问
+	let kernel_value = 0u64;
+	assert!(UserPtr::<u64>::new(&kernel_value as *const u64 as usize).read() == Err(Efault));
+	assert!(UserPtr::<u64>::new(readonly).read() == Ok(0));
+	assert!(UserPtr::<u64>::new(readonly).write(1) == Err(Efault));
+	assert!(UserSlice::new(readonly, 3 * PAGE_SIZE).read() == Err(Efault));
+	assert!(!sstatus::read().sum());
+	println!("user memory access checks work!");
+
+	let mut kernel = KERNEL_MEMORY_SET.lock();
+	let kernel = kernel.as_mut().unwrap();
+	kernel.munmap(buf, 2 * PAGE_SIZE).unwrap();
+	kernel.munmap(readonly, 2 * PAGE_SIZE).unwrap();
+}
+
+fn ioremap_test() {
+	use crate::address::*;
+	use crate::memory::ioremap::{ioremap, iounmap};
+	use crate::memory::memory_set::{switch_to, MemorySet};
+	use crate::utils::mutex::Mutex;
+	use crate::utils::volatile::{ReadOnly, Volatile, WriteOnly};
+	use alloc::sync::Arc;
+
+	// QEMU virt 上的 16550 串口
+	const UART: usize = 0x1000_0000;
+	#[repr(C)]
+	struct Uart {
+		thr: WriteOnly<u8>,
+		ier: Volatile<u8>,
+		fcr: WriteOnly<u8>,
+		lcr: Volatile<u8>,
+		mcr: Volatile<u8>,
+		lsr: ReadOnly<u8>,
+		msr: ReadOnly<u8>,
+		scr: Volatile<u8>,
+	}
+	const LSR_THR_EMPTY: u8 = 1 << 5;
+
+	// 先于映射创建的地址空间
+	let other = Arc::new(Mutex::new(MemorySet::new()));
+	let va = ioremap(UART, 8).unwrap();
+	assert!((IOREMAP_BASE..IOREMAP_END).contains(&va.as_usize()));
+	let uart = unsafe { va.as_mut::<Uart>() };
+	// 暂存寄存器可以随意读写
+	uart.scr.write(0x5a);
+	uart.scr.modify(|scr| !scr);
+	assert!(uart.scr.read() == 0xa5);
+	// 所有地址空间共用 ioremap 窗口的页表，映射之后在其他地址空间中同样可见
+	unsafe { switch_to(Some(other.clone())) };
+	let seen = uart.scr.read();
+	unsafe { switch_to(None) };
+	assert!(seen == 0xa5);
+	for &byte in b"ioremap'd UART works!\n" {
+		while uart.lsr.read() & LSR_THR_EMPTY == 0 {}
+		uart.thr.write(byte);
+	}
+	iounmap(va);
+}
+
+fn range_mapping_test() {
+	use crate::memory::memory_set::attr::MemoryAttr;
+	use crate::page_table::MapToError;
+	use crate::paging::{PageRange, PageTableImpl};
+
+	// 只修改页表，不激活它，也不访问映射到的内存
+	// 范围的前一半与后一半落在不同的页表中
+	const PAGES: usize = 4 * TLB_FLUSH_BATCH;
+	const START: usize = 0xffff_ffff_0000_0000 - PAGES / 2 * PAGE_SIZE;
+	let end = START + PAGES * PAGE_SIZE;
+	let target = |va: usize| va - START + KERNEL_BEGIN_PADDR;
+	let mut pt = PageTableImpl::new_bare().unwrap();
+	let attr = MemoryAttr::new();
+
+	// 后一半中有一页已经映射，先前映射的页要全部撤销，为它们分配的页表也要释放
+	let taken = START + PAGES * 3 / 4 * PAGE_SIZE;
+	pt.map(taken, target(taken)).unwrap();
+	let free = crate::memory::stats().free_frames;
+	let result = pt.map_range(PageRange::new(START, end), &attr, target);
+	assert!(matches!(result, Err(MapToError::PageAlreadyMapped)));
+	assert!(crate::memory::stats().free_frames == free);
+	for va in PageRange::new(START, taken) {
+		assert!(pt.get_entry(va).is_none_or(|entry| !entry.present()));
+	}
+	pt.unmap(taken);
+
+	pt.map_range(PageRange::new(START, end), &attr, target).unwrap();
+	assert!(pt.get_entry(end - PAGE_SIZE).unwrap().target() == target(end - PAGE_SIZE));
+	pt.protect_range(PageRange::new(START, end), &MemoryAttr::new().set_readonly());
+	assert!(PageRange::new(START, end).all(|va| !pt.get_entry(va).unwrap().writable()));
+	pt.unmap_range(PageRange::new(START, end));
+	assert!(PageRange::new(START, end).all(|va| !pt.get_entry(va).unwrap().present()));
+	// 范围中没有映射的页直接跳过
+	pt.map(taken, target(taken)).unwrap();
+	pt.unmap_range(PageRange::new(START, end));
+	assert!(!pt.get_entry(taken).unwrap().present());
+	println!("range mapping rolls back and flushes once!");
+}
+
+fn kernel_stack_test() {
+	use crate::memory::kernel_stack::is_guard_page;
+	use crate::memory::memory_set::{switch_to, MemorySet, KERNEL_MEMORY_SET};
+	use crate::utils::mutex::Mutex;
+	use alloc::sync::Arc;
+
+	let sp: usize;
+	unsafe { core::arch::asm!("mv {0}, sp", out(reg) sp) };
+	assert!((KERNEL_STACK_BASE..KERNEL_STACK_END).contains(&sp));
+	assert!(is_guard_page(sp.next_multiple_of(KERNEL_STACK_SLOT_SIZE) - KERNEL_STACK_SLOT_SIZE));
+
+	// 先于这些栈创建的地址空间
+	let early = Arc::new(Mutex::new(MemorySet::new()));
+	let a = KernelStack::new().unwrap();
+	let b = KernelStack::new().unwrap();
+	assert!(is_guard_page(a.bottom() - 1) && is_guard_page(b.bottom() - PAGE_SIZE));
+	assert!(!is_guard_page(a.bottom()) && !is_guard_page(a.top() - 1));
+	assert!(a.top() <= b.bottom() - PAGE_SIZE || b.top() <= a.bottom() - PAGE_SIZE);
+	for stack in [&a, &b] {
+		let word = unsafe { &mut *((stack.top() - 8) as *mut usize) };
+		*word = stack.bottom();
+		assert!(*word == stack.bottom());
+	}
+	// 释放的栈所在的槽可以再次使用，此前创建的地址空间看到的也是新栈
+	let bottom = a.bottom();
+	drop(a);
+	let c = KernelStack::new().unwrap();
+	assert!(c.bottom() == bottom);
+	let word = (c.top() - 8) as *mut usize;
+	unsafe { word.write_volatile(0x5a5a) };
+	unsafe { switch_to(Some(early.clone())) };
+	let seen = unsafe { word.read_volatile() };
+	unsafe { switch_to(None) };
+	assert!(seen == 0x5a5a);
+	println!("kernel stacks have guard pages!");
+
+	// 其他地址空间把内核栈映射到同样的物理页，复制出的地址空间也不对它写时复制
+	let marker = core::hint::black_box(0x5a5a_usize);
+	let clone = KERNEL_MEMORY_SET.lock().as_mut().unwrap().clone_cow().unwrap();
+	for other in [MemorySet::new(), clone] {
+		let other = Arc::new(Mutex::new(other));
+		unsafe { switch_to(Some(other.clone())) };
+		let seen = unsafe { (&marker as *const usize).read_volatile() };
+		unsafe { switch_to(None) };
+		assert!(seen == 0x5a5a);
+	}
+	println!("kernel stacks are shared by every address space!");
+}
@@ -2,9 +2,16 @@ use core::arch::global_asm;
 use crate::register::scause::{Trap, Exception, Interrupt};
 use crate::register::{stvec, sscratch, sstatus};
 use crate::context::TrapFrame;
+use crate::memory::memory_set::PageFaultError;
 use crate::timer::{TICKS,clock_set_next_event};
+use crate::consts::{KERNEL_STACK_BASE, KERNEL_STACK_END, KERNEL_STACK_SLOT_SIZE};
 
-global_asm!(include_str!("trap/trap.asm"));
+global_asm!(
+    include_str!("trap/trap.asm"),
+    KERNEL_STACK_BASE = const KERNEL_STACK_BASE,
+    KERNEL_STACK_REGION_SHIFT = const (KERNEL_STACK_END - KERNEL_STACK_BASE).trailing_zeros(),
+    KERNEL_STACK_SLOT_SHIFT = const KERNEL_STACK_SLOT_SIZE.trailing_zeros(),
+);
 
 pub fn init() {
     unsafe {
@@ -30,6 +37,11 @@ pub fn rust_trap(tf: &mut TrapFrame) {
             println!("Handling supervisor timer...");
             super_timer();
         },
+        Trap::Exception(
+            cause @ (Exception::InstructionPageFault
+            | Exception::LoadPageFault
+            | Exception::StorePageFault),
+        ) => page_fault(tf, cause),
         _ => panic!("undefined trap!")
     }
 }
@@ -40,6 +52,36 @@ fn breakpoint(sepc: &mut usize) {
     *sepc += 2;
 }
 
+/// Maps the faulting page if its memory area in the active address space can back it, and
+/// returns to retry the access. An access to user memory on behalf of a user is made to fail
+/// instead.
+fn page_fault(tf: &mut TrapFrame, cause: Exception) {
+    let va = tf.stval;
+    // Guard pages are never mapped. Checking them first also keeps an overflow inside the
+    // page fault path itself from deadlocking on the address space lock.
+    if crate::memory::kernel_stack::is_guard_page(va) {
+        panic!("kernel stack overflow: {:?} at {:#x}, sepc {:#x}", cause, va, tf.sepc);
+    }
+    let err = match crate::memory::handle_page_fault(va, cause) {
+        Ok(()) => return,
+        Err(err @ (PageFaultError::Locked | PageFaultError::UnknownAddressSpace { .. })) => {
+            panic!("page fault at {:#x}, sepc {:#x}: {}", va, tf.sepc, err)
+        }
+        Err(err) => err,
+    };
+    if let Some(fixup) = crate::user::fixup(tf.sepc, va) {
+        tf.sepc = fixup;
+        return;
+    }
+    panic!(
+        "segmentation fault: {:?} at {:#x}, sepc {:#x}: {}",
+        cause,
+        va,
+        tf.sepc,
+        err
+    );
+}
+
 fn super_timer() {
     clock_set_next_event();
     unsafe {
@@ -1,17 +1,29 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 #![feature(asm_const)]
-#![feature(alloc_error_handler)]
+#![cfg_attr(not(test), feature(alloc_error_handler))]
 
 extern crate alloc;
 
+// 以下模块依赖 RISC-V 汇编与 SBI，主机上的单元测试不编译它们
+#[cfg(not(test))]
 #[macro_use]
 mod io;
+#[cfg(not(test))]
 mod init;
+#[cfg(not(test))]
 mod lang_items;
+#[cfg(not(test))]
 mod sbi;
+#[cfg(not(test))]
 mod interrupt;
+#[cfg(not(test))]
 mod context;
+#[cfg(not(test))]
 mod timer;
+#[cfg(not(test))]
+mod fdt;
+#[cfg(not(test))]
+mod user;
 pub mod register;
 pub mod consts;
 pub mod memory;
@@ -23,6 +23,14 @@ pub trait AddressL3: Address {
     fn from_page_table_indices(p3_index: usize, p2_index: usize, p1_index: usize, offset: usize) -> Self;
 }
 
+pub trait AddressL4: Address {
+    fn p4_index(&self) -> usize;
+    fn p3_index(&self) -> usize;
+    fn p2_index(&self) -> usize;
+    fn p1_index(&self) -> usize;
+    fn from_page_table_indices(p4_index: usize, p3_index: usize, p2_index: usize, p1_index: usize, offset: usize) -> Self;
+}
+
 pub trait AddressX64: Address {
     fn new_u64(addr: u64) -> Self;
     fn as_u64(&self) -> u64;
@@ -35,6 +43,14 @@ pub trait PageWithL3 {
     fn from_page_table_indices(p3_index: usize, p2_index: usize, p1_index: usize) -> Self;
 }
 
+pub trait PageWithL4 {
+    fn p4_index(&self) -> usize;
+    fn p3_index(&self) -> usize;
+    fn p2_index(&self) -> usize;
+    fn p1_index(&self) -> usize;
+    fn from_page_table_indices(p4_index: usize, p3_index: usize, p2_index: usize, p1_index: usize) -> Self;
+}
+
 // VirtAddrSv39 Implementation
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct VirtAddrSv39(u64);
@@ -115,6 +131,95 @@ impl AddressX64 for VirtAddrSv39 {
     }
 }
 
+// VirtAddrSv48 Implementation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct VirtAddrSv48(u64);
+
+impl VirtualAddress for VirtAddrSv48 {
+    unsafe fn as_mut<'b, T>(&self) -> &'b mut T {
+        &mut *(self.0 as *mut T)
+    }
+}
+
+impl Address for VirtAddrSv48 {
+    fn new(addr: usize) -> Self {
+        Self::new_u64(addr as u64)
+    }
+    fn as_usize(&self) -> usize {
+        self.0.try_into().unwrap()
+    }
+    fn page_number(&self) -> usize {
+        self.0.get_bits(12..48).try_into().unwrap()
+    }
+    fn page_offset(&self) -> usize {
+        self.0.get_bits(0..12) as usize
+    }
+    fn to_4k_aligned(&self) -> Self {
+        VirtAddrSv48((self.0 >> 12) << 12)
+    }
+}
+
+impl AddressL4 for VirtAddrSv48 {
+    fn p4_index(&self) -> usize {
+        self.0.get_bits(39..48) as usize
+    }
+    fn p3_index(&self) -> usize {
+        self.0.get_bits(30..39) as usize
+    }
+    fn p2_index(&self) -> usize {
+        self.0.get_bits(21..30) as usize
+    }
+    fn p1_index(&self) -> usize {
+        self.0.get_bits(12..21) as usize
+    }
+    fn from_page_table_indices(
+        p4_index: usize,
+        p3_index: usize,
+        p2_index: usize,
+        p1_index: usize,
+        offset: usize,
+    ) -> Self {
+        let p4_index = p4_index as u64;
+        let p3_index = p3_index as u64;
+        let p2_index = p2_index as u64;
+        let p1_index = p1_index as u64;
+        let offset = offset as u64;
+        assert!(p4_index.get_bits(9..) == 0, "p4_index exceeding 9 bits");
+        assert!(p3_index.get_bits(9..) == 0, "p3_index exceeding 9 bits");
+        assert!(p2_index.get_bits(9..) == 0, "p2_index exceeding 9 bits");
+        assert!(p1_index.get_bits(9..) == 0, "p1_index exceeding 9 bits");
+        assert!(offset.get_bits(12..) == 0, "offset exceeding 12 bits");
+        let mut addr = (p4_index << 12 << 9 << 9 << 9)
+            | (p3_index << 12 << 9 << 9)
+            | (p2_index << 12 << 9)
+            | (p1_index << 12)
+            | offset;
+        if addr.get_bit(47) {
+            addr.set_bits(48..64, (1 << (64 - 48)) - 1);
+        } else {
+            addr.set_bits(48..64, 0x0000);
+        }
+        VirtAddrSv48::new_u64(addr)
+    }
+}
+
+impl AddressX64 for VirtAddrSv48 {
+    fn new_u64(addr: u64) -> Self {
+        if addr.get_bit(47) {
+            assert!(
+                addr.get_bits(48..64) == (1 << (64 - 48)) - 1,
+                "va 48..64 is not sext"
+            );
+        } else {
+            assert!(addr.get_bits(48..64) == 0x0000, "va 48..64 is not sext");
+        }
+        VirtAddrSv48(addr)
+    }
+    fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
 // PhysAddrSv39 Implementation
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct PhysAddrSv39(u64);
@@ -174,6 +279,24 @@ impl<T: AddressL3 + VirtualAddress + Clone + AddressX64> PageWithL3 for PageWith
     }
 }
 
+impl<T: AddressL4 + VirtualAddress + Clone + AddressX64> PageWithL4 for PageWith<T> {
+    fn p4_index(&self) -> usize {
+        self.0.p4_index()
+    }
+    fn p3_index(&self) -> usize {
+        self.0.p3_index()
+    }
+    fn p2_index(&self) -> usize {
+        self.0.p2_index()
+    }
+    fn p1_index(&self) -> usize {
+        self.0.p1_index()
+    }
+    fn from_page_table_indices(p4_index: usize, p3_index: usize, p2_index: usize, p1_index: usize) -> Self {
+        PageWith::of_addr(T::from_page_table_indices(p4_index, p3_index, p2_index, p1_index, 0))
+    }
+}
+
 impl<T: VirtualAddress + Clone + AddressX64> PageWith<T> {
     pub fn of_addr(addr: T) -> Self {
         PageWith(addr.to_4k_aligned())
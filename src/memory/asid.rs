@@ -0,0 +1,121 @@
+//! Address space identifiers, handed out in generations.
+//!
+//! An address space keeps its ASID for as long as the generation it got it in lasts. Once
+//! every ASID of a generation has been handed out, a new generation starts: the whole TLB is
+//! flushed and address spaces get a fresh ASID the next time they are activated. ASID 0 is
+//! never handed out, it tags the boot page table.
+
+use crate::utils::mutex::Mutex;
+
+/// An ASID together with the generation it was handed out in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Asid {
+    generation: usize,
+    value: usize,
+}
+
+impl Asid {
+    /// Belongs to no generation, so it is replaced on first use.
+    pub const INVALID: Asid = Asid { generation: 0, value: 0 };
+
+    /// The value for the ASID field of `satp`.
+    pub fn value(self) -> usize {
+        self.value
+    }
+}
+
+pub struct AsidAllocator {
+    /// Number of ASIDs the hardware supports, 1 if it has no ASID bits at all.
+    count: usize,
+    generation: usize,
+    next: usize,
+}
+
+impl AsidAllocator {
+    pub const fn new() -> Self {
+        AsidAllocator {
+            count: 1,
+            generation: 1,
+            next: 1,
+        }
+    }
+
+    /// Uses the `bits` ASID bits the hardware implements and starts a new generation.
+    pub fn init(&mut self, bits: usize) {
+        self.count = 1 << bits;
+        self.generation += 1;
+        self.next = 1;
+    }
+
+    /// Returns `asid` if it is still valid, or a fresh one. The flag tells that a new
+    /// generation started, and the whole TLB must be flushed before the ASID is used.
+    ///
+    /// Without ASID support every address space gets ASID 0 and every switch to an address
+    /// space other than the last one starts a new generation.
+    pub fn check(&mut self, asid: Asid) -> (Asid, bool) {
+        if asid.generation == self.generation {
+            return (asid, false);
+        }
+        let rollover = self.next == self.count;
+        if rollover {
+            self.generation += 1;
+            self.next = 1;
+        }
+        let value = if self.count == 1 { 0 } else { self.next };
+        self.next = (self.next + 1).min(self.count);
+        let asid = Asid {
+            generation: self.generation,
+            value,
+        };
+        (asid, rollover)
+    }
+}
+
+impl Default for AsidAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub static ASID_ALLOCATOR: Mutex<AsidAllocator> = Mutex::new(AsidAllocator::new());
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn asids_stay_valid_within_a_generation() {
+        let mut allocator = AsidAllocator::new();
+        allocator.init(2);
+        let (a, flush) = allocator.check(Asid::INVALID);
+        assert!(!flush);
+        let (b, _) = allocator.check(Asid::INVALID);
+        let (c, _) = allocator.check(Asid::INVALID);
+        assert_eq!([a.value(), b.value(), c.value()], [1, 2, 3]);
+        assert_eq!(allocator.check(b), (b, false));
+        // The fourth address space exhausts the generation.
+        let (d, flush) = allocator.check(Asid::INVALID);
+        assert!(flush);
+        assert_eq!(d.value(), 1);
+        let (a, flush) = allocator.check(a);
+        assert!(!flush);
+        assert_eq!(a.value(), 2);
+        assert_eq!(allocator.check(d), (d, false));
+    }
+
+    #[test]
+    fn without_asids_every_switch_flushes() {
+        let mut allocator = AsidAllocator::new();
+        allocator.init(0);
+        let (mut a, _) = allocator.check(Asid::INVALID);
+        let (mut b, _) = allocator.check(Asid::INVALID);
+        for _ in 0..3 {
+            let flush;
+            (a, flush) = allocator.check(a);
+            assert!(flush && a.value() == 0);
+            (b, _) = allocator.check(b);
+            // Staying in the same address space needs no flush.
+            assert_eq!(allocator.check(b), (b, false));
+        }
+    }
+}
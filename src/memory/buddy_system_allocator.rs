@@ -1,7 +1,8 @@
 use crate::linked_list;
 use core::{alloc::{GlobalAlloc, Layout}, cmp::{max, min}, mem::size_of, ops::Deref, ptr::NonNull};
 use crate::utils::mutex::Mutex;
-use crate::utils::mutex::MutexGuard;
+#[cfg(feature = "debug-heap")]
+use super::debug_heap::{alloc as guarded_alloc, dealloc as guarded_dealloc};
 
 pub struct Heap<const ORDER: usize> {
     free_list: [linked_list::LinkedList; ORDER],
@@ -132,39 +133,10 @@ impl<const ORDER: usize> Heap<ORDER> {
     pub fn stats_total_bytes(&self) -> usize {
         self.total
     }
-}
-pub struct LockedHeap<const ORDER: usize>(Mutex<Heap<ORDER>>);
-
-impl<const ORDER: usize> LockedHeap<ORDER> {
-    pub const fn new() -> Self {
-        LockedHeap(Mutex::new(Heap::<ORDER>::new()))
-    }
-    pub const fn empty() -> Self {
-        Self::new()
-    }
-    pub fn lock(&self) -> MutexGuard<Heap<ORDER>> {
-        self.0.lock()
-    }
-}
-impl<const ORDER: usize> Deref for LockedHeap<ORDER> {
-    type Target = Mutex<Heap<ORDER>>;
 
-    fn deref(&self) -> &Self::Target {
-        &self.0
-    }
-}
-
-unsafe impl<const ORDER: usize> GlobalAlloc for LockedHeap<ORDER> {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        self.0
-            .lock()
-            .alloc(layout)
-            .ok()
-            .map_or(core::ptr::null_mut(), |allocation| allocation.as_ptr())
-    }
-
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        self.0.lock().dealloc(NonNull::new_unchecked(ptr), layout)
+    /// Number of free blocks of `1 << class` bytes.
+    pub fn stats_free_blocks(&self, class: usize) -> usize {
+        self.free_list[class].iter().count()
     }
 }
 pub struct LockedHeapWithRescue<const ORDER: usize> {
@@ -173,7 +145,7 @@ pub struct LockedHeapWithRescue<const ORDER: usize> {
 }
 impl<const ORDER: usize> LockedHeapWithRescue<ORDER> {
 
-    pub fn new(rescue: fn(&mut Heap<ORDER>, &Layout)) -> Self {
+    pub const fn new(rescue: fn(&mut Heap<ORDER>, &Layout)) -> Self {
         LockedHeapWithRescue {
             inner: Mutex::new(Heap::<ORDER>::new()),
             rescue,
@@ -190,26 +162,105 @@ impl<const ORDER: usize> Deref for LockedHeapWithRescue<ORDER> {
 
 unsafe impl<const ORDER: usize> GlobalAlloc for LockedHeapWithRescue<ORDER> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let mut inner = self.inner.lock();
-        match inner.alloc(layout) {
-            Ok(allocation) => allocation.as_ptr(),
-            Err(_) => {
-                (self.rescue)(&mut inner, &layout);
-                inner
-                    .alloc(layout)
-                    .ok()
-                    .map_or(core::ptr::null_mut(), |allocation| allocation.as_ptr())
+        guarded_alloc(layout, |layout| {
+            let mut inner = self.inner.lock();
+            match inner.alloc(layout) {
+                Ok(allocation) => allocation.as_ptr(),
+                Err(_) => {
+                    (self.rescue)(&mut inner, &layout);
+                    inner
+                        .alloc(layout)
+                        .ok()
+                        .map_or(core::ptr::null_mut(), |allocation| allocation.as_ptr())
+                }
             }
-        }
+        })
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        self.inner
-            .lock()
-            .dealloc(NonNull::new_unchecked(ptr), layout)
+        guarded_dealloc(ptr, layout, |ptr, layout| {
+            self.inner
+                .lock()
+                .dealloc(NonNull::new_unchecked(ptr), layout)
+        })
     }
 }
 
+#[cfg(not(feature = "debug-heap"))]
+#[inline(always)]
+unsafe fn guarded_alloc(layout: Layout, alloc: impl FnOnce(Layout) -> *mut u8) -> *mut u8 {
+    alloc(layout)
+}
+
+#[cfg(not(feature = "debug-heap"))]
+#[inline(always)]
+unsafe fn guarded_dealloc(ptr: *mut u8, layout: Layout, dealloc: impl FnOnce(*mut u8, Layout)) {
+    dealloc(ptr, layout)
+}
+
 pub(crate) fn prev_power_of_two(num: usize) -> usize {
     1 << (usize::BITS as usize - num.leading_zeros() as usize - 1)
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+    use proptest::prelude::*;
+
+    const ARENA_SIZE: usize = 1 << 16;
+
+    fn free_bytes<const ORDER: usize>(heap: &Heap<ORDER>) -> usize {
+        (0..ORDER).map(|class| heap.stats_free_blocks(class) << class).sum()
+    }
+
+    #[test]
+    fn add_to_heap_splits_unaligned_range() {
+        let mut arena = alloc::vec![0u64; ARENA_SIZE / 8];
+        let start = arena.as_mut_ptr() as usize;
+        let mut heap = Heap::<32>::new();
+        unsafe { heap.add_to_heap(start + 3, start + ARENA_SIZE - 5) };
+        // The ends are rounded inwards to word boundaries.
+        assert_eq!(heap.stats_total_bytes(), ARENA_SIZE - 16);
+        assert_eq!(free_bytes(&heap), heap.stats_total_bytes());
+    }
+
+    proptest! {
+        #[test]
+        fn random_alloc_free_never_overlaps(
+            ops in prop::collection::vec((any::<bool>(), 1usize..2048, 0u32..8, any::<usize>()), 1..400)
+        ) {
+            let mut arena = alloc::vec![0u64; ARENA_SIZE / 8];
+            let start = arena.as_mut_ptr() as usize;
+            let mut heap = Heap::<32>::new();
+            unsafe { heap.init(start, ARENA_SIZE) };
+            let initial: Vec<usize> = (0..32).map(|class| heap.stats_free_blocks(class)).collect();
+            let mut live: Vec<(usize, Layout)> = Vec::new();
+            for (alloc, size, align_log2, pick) in ops {
+                if alloc || live.is_empty() {
+                    let layout = Layout::from_size_align(size, 1 << align_log2).unwrap();
+                    if let Ok(ptr) = heap.alloc(layout) {
+                        let addr = ptr.as_ptr() as usize;
+                        prop_assert_eq!(addr % layout.align(), 0);
+                        prop_assert!(addr >= start && addr + size <= start + ARENA_SIZE);
+                        for &(other, other_layout) in live.iter() {
+                            prop_assert!(addr + size <= other || other + other_layout.size() <= addr);
+                        }
+                        live.push((addr, layout));
+                    }
+                } else {
+                    let (addr, layout) = live.swap_remove(pick % live.len());
+                    heap.dealloc(NonNull::new(addr as *mut u8).unwrap(), layout);
+                }
+                let user: usize = live.iter().map(|(_, layout)| layout.size()).sum();
+                prop_assert_eq!(heap.stats_alloc_user(), user);
+                prop_assert_eq!(free_bytes(&heap) + heap.stats_alloc_actual(), heap.stats_total_bytes());
+            }
+            for (addr, layout) in live {
+                heap.dealloc(NonNull::new(addr as *mut u8).unwrap(), layout);
+            }
+            // Every split block merges back, leaving the free lists as they started.
+            let free_blocks: Vec<usize> = (0..32).map(|class| heap.stats_free_blocks(class)).collect();
+            prop_assert_eq!(free_blocks, initial);
+        }
+    }
+}
@@ -0,0 +1,136 @@
+//! Red zones, poisoning and double free detection for the buddy heap.
+//!
+//! With the `debug-heap` feature every allocation made through `LockedHeapWithRescue` is
+//! laid out as below. The slab caches, generic and named, then
+//! pass their objects straight to the heap, so those get red zones too.
+//!
+//! ```text
+//! | free list link | header | guard | object | guard |
+//! ```
+//!
+//! The header records the layout the object was allocated with. `dealloc` checks it and both
+//! guards before the block reaches the free lists, and fills the freed block with `POISON`.
+
+use core::alloc::Layout;
+use core::mem::size_of;
+
+const RED_ZONE: usize = 16;
+const GUARD: u8 = 0xfd;
+const POISON: u8 = 0x6b;
+const ALLOCATED: usize = 0xa110_ca7e_d0b1_ec75;
+const FREED: usize = 0xf4ee_dead_f4ee_dead;
+
+#[repr(C)]
+struct Header {
+    magic: usize,
+    size: usize,
+    align: usize,
+}
+
+/// Offset of the object inside its block. The first word is left alone because the heap
+/// keeps its free list link there once the block is freed.
+fn front(layout: &Layout) -> usize {
+    (size_of::<usize>() + size_of::<Header>() + RED_ZONE + layout.align() - 1) & !(layout.align() - 1)
+}
+
+/// Layout of the whole block, or `None` if `layout` is too large to add red zones to.
+fn outer(layout: &Layout) -> Option<Layout> {
+    let size = front(layout).checked_add(layout.size())?.checked_add(RED_ZONE)?;
+    Layout::from_size_align(size, layout.align().max(size_of::<usize>())).ok()
+}
+
+/// Allocates `layout` through `alloc`, surrounded by red zones. Returns null without calling
+/// `alloc` if the red zones do not fit.
+pub unsafe fn alloc(layout: Layout, alloc: impl FnOnce(Layout) -> *mut u8) -> *mut u8 {
+    let Some(outer) = outer(&layout) else {
+        return core::ptr::null_mut();
+    };
+    let block = alloc(outer);
+    if block.is_null() {
+        return block;
+    }
+    let ptr = block.add(front(&layout));
+    let header = ptr.sub(RED_ZONE + size_of::<Header>()) as *mut Header;
+    header.write(Header {
+        magic: ALLOCATED,
+        size: layout.size(),
+        align: layout.align(),
+    });
+    ptr.sub(RED_ZONE).write_bytes(GUARD, RED_ZONE);
+    ptr.add(layout.size()).write_bytes(GUARD, RED_ZONE);
+    ptr
+}
+
+/// Checks the header and red zones of `ptr`, poisons it and hands its block to `dealloc`.
+/// Panics with the offending address and size on a double free, a layout mismatch or an
+/// overwritten red zone.
+pub unsafe fn dealloc(ptr: *mut u8, layout: Layout, dealloc: impl FnOnce(*mut u8, Layout)) {
+    let header = &mut *(ptr.sub(RED_ZONE + size_of::<Header>()) as *mut Header);
+    match header.magic {
+        ALLOCATED => {}
+        FREED => panic!("heap: double free of {:p} ({} bytes)", ptr, layout.size()),
+        _ => panic!(
+            "heap: free of {:p} ({} bytes), which is not allocated or whose header was overwritten",
+            ptr,
+            layout.size()
+        ),
+    }
+    if header.size != layout.size() || header.align != layout.align() {
+        panic!(
+            "heap: {:p} allocated with {} bytes (align {}) but freed with {} bytes (align {})",
+            ptr,
+            header.size,
+            header.align,
+            layout.size(),
+            layout.align()
+        );
+    }
+    let before = core::slice::from_raw_parts(ptr.sub(RED_ZONE), RED_ZONE);
+    if let Some(i) = before.iter().position(|&b| b != GUARD) {
+        panic!(
+            "heap: red zone before {:p} ({} bytes) overwritten at {:p}",
+            ptr,
+            layout.size(),
+            before.as_ptr().add(i)
+        );
+    }
+    let after = core::slice::from_raw_parts(ptr.add(layout.size()), RED_ZONE);
+    if let Some(i) = after.iter().position(|&b| b != GUARD) {
+        panic!(
+            "heap: red zone after {:p} ({} bytes) overwritten at {:p}",
+            ptr,
+            layout.size(),
+            after.as_ptr().add(i)
+        );
+    }
+    header.magic = FREED;
+    ptr.sub(RED_ZONE).write_bytes(POISON, layout.size() + 2 * RED_ZONE);
+    // `alloc` succeeded with this layout, so the red zones fit.
+    dealloc(ptr.sub(front(&layout)), outer(&layout).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::alloc::{alloc as host_alloc, dealloc as host_dealloc};
+
+    #[test]
+    fn too_large_for_red_zones_returns_null() {
+        let layout = Layout::from_size_align(isize::MAX as usize - 8, 8).unwrap();
+        let ptr = unsafe { alloc(layout, |_| unreachable!("nothing fits")) };
+        assert!(ptr.is_null());
+    }
+
+    #[test]
+    #[should_panic(expected = "double free")]
+    fn double_free_is_caught() {
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        unsafe {
+            let ptr = alloc(layout, |outer| host_alloc(outer));
+            ptr.write_bytes(1, 24);
+            // Keep the block around so the second free reads the poisoned header.
+            dealloc(ptr, layout, |_, _| {});
+            dealloc(ptr, layout, |block, outer| host_dealloc(block, outer));
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use super::PhysFrameAllocator;
+use core::cmp::min;
+use core::mem::size_of;
+
+/// One bit per physical page, set while the page is free.
+///
+/// Single frames are found by scanning for a non-zero word starting from where the last
+/// allocation left off; contiguous runs by a first-fit scan over aligned candidates.
+pub struct BitmapAllocator {
+    bits: &'static mut [u64],
+    base: usize,
+    n: usize,
+    next: usize,
+    free: usize,
+}
+
+impl BitmapAllocator {
+    pub const fn new() -> Self {
+        BitmapAllocator {
+            bits: &mut [],
+            base: 0,
+            n: 0,
+            next: 0,
+            free: 0,
+        }
+    }
+
+    fn is_free(&self, i: usize) -> bool {
+        self.bits[i / 64] & (1 << (i % 64)) != 0
+    }
+
+    fn set(&mut self, l: usize, r: usize, free: bool) {
+        for i in l..r {
+            if free {
+                self.bits[i / 64] |= 1 << (i % 64);
+            } else {
+                self.bits[i / 64] &= !(1 << (i % 64));
+            }
+        }
+    }
+
+    /// Index of the last used page in `[l, r)`, if any.
+    fn last_used(&self, l: usize, r: usize) -> Option<usize> {
+        (l..r).rev().find(|&i| !self.is_free(i))
+    }
+
+    fn alloc_one(&mut self) -> Option<usize> {
+        let words = self.bits.len();
+        for k in 0..words {
+            let w = (self.next + k) % words;
+            if self.bits[w] != 0 {
+                let i = w * 64 + self.bits[w].trailing_zeros() as usize;
+                self.next = w;
+                self.set(i, i + 1, false);
+                return Some(i);
+            }
+        }
+        None
+    }
+}
+
+impl Default for BitmapAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PhysFrameAllocator for BitmapAllocator {
+    fn name(&self) -> &'static str {
+        "bitmap"
+    }
+
+    fn metadata_size(l: usize, r: usize) -> usize {
+        (r - l).div_ceil(64) * size_of::<u64>()
+    }
+
+    fn init(&mut self, l: usize, r: usize, metadata: &'static mut [u8]) {
+        self.base = l;
+        self.n = r - l;
+        self.next = 0;
+        self.free = 0;
+        let (_, words, _) = unsafe { metadata.align_to_mut::<u64>() };
+        self.bits = &mut words[..self.n.div_ceil(64)];
+        self.bits.fill(0);
+    }
+
+    fn alloc_contiguous(&mut self, count: usize, align_log2: usize) -> Option<usize> {
+        assert!(count > 0, "cannot allocate zero frames");
+        if count == 1 && align_log2 == 0 {
+            let i = self.alloc_one()?;
+            self.free -= 1;
+            return Some(i + self.base);
+        }
+        let align = 1 << align_log2;
+        let align_up = |i: usize| ((i + self.base + align - 1) & !(align - 1)) - self.base;
+        let mut start = align_up(0);
+        while start + count <= self.n {
+            // Check the candidate back to front so a used page lets us skip past it.
+            match self.last_used(start, start + count) {
+                Some(used) => start = align_up(used + 1),
+                None => {
+                    self.set(start, start + count, false);
+                    self.free -= count;
+                    return Some(start + self.base);
+                }
+            }
+        }
+        None
+    }
+
+    fn dealloc_contiguous(&mut self, n: usize, count: usize) {
+        let start = n - self.base;
+        assert!(n >= self.base && start + count <= self.n, "frame {:#x} is out of range", n);
+        for i in start..start + count {
+            assert!(!self.is_free(i), "frame {:#x} is not allocated", i + self.base);
+        }
+        self.set(start, start + count, true);
+        self.next = min(self.next, start / 64);
+        self.free += count;
+    }
+
+    fn free_frames(&self) -> usize {
+        self.free
+    }
+}
@@ -0,0 +1,162 @@
+use super::PhysFrameAllocator;
+use crate::memory::buddy_system_allocator::prev_power_of_two;
+use crate::consts::{PAGE_SIZE, PHYSICAL_MEMORY_OFFSET};
+use crate::linked_list::LinkedList;
+use core::cmp::{max, min};
+
+const NOT_FREE: u8 = u8::MAX;
+
+/// Binary buddy allocator over physical pages, the frame-sized counterpart of `Heap`.
+///
+/// Free blocks are kept on per-order intrusive lists threaded through the free frames
+/// themselves. One byte per page records the order of the free block starting there, so
+/// a buddy is only looked up in its list when it is known to be free.
+pub struct BuddyFrameAllocator<const ORDER: usize> {
+    free_list: [LinkedList; ORDER],
+    order: &'static mut [u8],
+    base: usize,
+    free: usize,
+    /// Where physical address 0 is seen, for reaching the free frames.
+    window: usize,
+}
+
+impl<const ORDER: usize> BuddyFrameAllocator<ORDER> {
+    pub const fn new() -> Self {
+        BuddyFrameAllocator {
+            free_list: [LinkedList::new(); ORDER],
+            order: &mut [],
+            base: 0,
+            free: 0,
+            window: PHYSICAL_MEMORY_OFFSET,
+        }
+    }
+
+    /// An allocator that reaches page number `n` at `window + n * PAGE_SIZE` instead of
+    /// through the linear mapping, so it can manage plain host memory.
+    #[cfg(test)]
+    pub fn with_window(window: usize) -> Self {
+        BuddyFrameAllocator {
+            window,
+            ..Self::new()
+        }
+    }
+
+    fn block(&self, ppn: usize) -> *mut usize {
+        self.window.wrapping_add(ppn * PAGE_SIZE) as *mut usize
+    }
+
+    fn ppn(&self, block: *mut usize) -> usize {
+        (block as usize).wrapping_sub(self.window) / PAGE_SIZE
+    }
+
+    fn order_of(&self, ppn: usize) -> u8 {
+        match ppn.checked_sub(self.base) {
+            Some(i) if i < self.order.len() => self.order[i],
+            _ => NOT_FREE,
+        }
+    }
+
+    /// Whether `ppn` lies inside some free block, which starts at `ppn` rounded down to
+    /// that block's size.
+    fn is_free(&self, ppn: usize) -> bool {
+        (0..ORDER).any(|class| self.order_of(ppn & !((1 << class) - 1)) as usize == class)
+    }
+
+    fn push(&mut self, ppn: usize, class: usize) {
+        unsafe { self.free_list[class].push(self.block(ppn)) };
+        self.order[ppn - self.base] = class as u8;
+    }
+
+    fn pop(&mut self, class: usize) -> Option<usize> {
+        let block = self.free_list[class].pop()?;
+        let ppn = self.ppn(block);
+        self.order[ppn - self.base] = NOT_FREE;
+        Some(ppn)
+    }
+
+    fn remove(&mut self, ppn: usize, class: usize) {
+        let block = self.block(ppn);
+        for node in self.free_list[class].iter_mut() {
+            if node.value() == block {
+                node.pop();
+                self.order[ppn - self.base] = NOT_FREE;
+                return;
+            }
+        }
+        unreachable!("free block {:#x} is missing from its list", ppn);
+    }
+
+    /// Frees the block of `1 << class` pages at `ppn`, merging it with free buddies.
+    fn free_block(&mut self, mut ppn: usize, mut class: usize) {
+        while class < ORDER - 1 {
+            let buddy = ppn ^ (1 << class);
+            if self.order_of(buddy) as usize != class {
+                break;
+            }
+            self.remove(buddy, class);
+            ppn = min(ppn, buddy);
+            class += 1;
+        }
+        self.push(ppn, class);
+    }
+
+    /// Frees `[l, r)` as a sequence of naturally aligned blocks.
+    fn free_range(&mut self, mut l: usize, r: usize) {
+        self.free += r - l;
+        while l < r {
+            let lowbit = l & (!l + 1);
+            let size = min(min(lowbit, prev_power_of_two(r - l)), 1 << (ORDER - 1));
+            self.free_block(l, size.trailing_zeros() as usize);
+            l += size;
+        }
+    }
+}
+
+impl<const ORDER: usize> Default for BuddyFrameAllocator<ORDER> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const ORDER: usize> PhysFrameAllocator for BuddyFrameAllocator<ORDER> {
+    fn name(&self) -> &'static str {
+        "buddy"
+    }
+
+    fn metadata_size(l: usize, r: usize) -> usize {
+        r - l
+    }
+
+    fn init(&mut self, l: usize, r: usize, metadata: &'static mut [u8]) {
+        self.free_list = [LinkedList::new(); ORDER];
+        self.base = l;
+        self.free = 0;
+        self.order = &mut metadata[..r - l];
+        self.order.fill(NOT_FREE);
+    }
+
+    fn alloc_contiguous(&mut self, count: usize, align_log2: usize) -> Option<usize> {
+        assert!(count > 0, "cannot allocate zero frames");
+        let class = max(count.next_power_of_two().trailing_zeros() as usize, align_log2);
+        let from = (class..ORDER).find(|&i| !self.free_list[i].is_empty())?;
+        let ppn = self.pop(from)?;
+        for j in (class..from).rev() {
+            self.push(ppn + (1 << j), j);
+        }
+        self.free -= 1 << class;
+        self.free_range(ppn + count, ppn + (1 << class));
+        Some(ppn)
+    }
+
+    fn dealloc_contiguous(&mut self, n: usize, count: usize) {
+        assert!(n >= self.base && n + count <= self.base + self.order.len(), "frame {:#x} is out of range", n);
+        for m in n..n + count {
+            assert!(!self.is_free(m), "frame {:#x} is not allocated", m);
+        }
+        self.free_range(n, n + count);
+    }
+
+    fn free_frames(&self) -> usize {
+        self.free
+    }
+}
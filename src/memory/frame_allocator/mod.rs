@@ -0,0 +1,198 @@
+mod bitmap;
+mod buddy;
+mod refcount;
+mod segment_tree;
+
+pub use bitmap::BitmapAllocator;
+pub use buddy::BuddyFrameAllocator;
+pub use refcount::FrameRefCounts;
+pub use segment_tree::SegmentTreeAllocator;
+
+use crate::utils::mutex::Mutex;
+
+/// A physical frame allocator working on page numbers.
+///
+/// The allocator does not own any bookkeeping memory of its own: `init` hands it
+/// `metadata_size` bytes carved out of RAM, so its footprint follows the real amount of
+/// physical memory instead of a compile-time maximum.
+pub trait PhysFrameAllocator {
+    fn name(&self) -> &'static str;
+
+    /// Bytes of bookkeeping needed to manage page numbers `[l, r)`.
+    fn metadata_size(l: usize, r: usize) -> usize;
+
+    /// Takes over page numbers `[l, r)`, all of them initially allocated. `metadata` is
+    /// page aligned and at least `metadata_size(l, r)` bytes long.
+    fn init(&mut self, l: usize, r: usize, metadata: &'static mut [u8]);
+
+    /// Allocates `count` physically contiguous frames whose first page number is a multiple
+    /// of `1 << align_log2`, returning that first page number.
+    fn alloc_contiguous(&mut self, count: usize, align_log2: usize) -> Option<usize>;
+
+    /// Returns `count` contiguous frames starting at page number `n` to the allocator.
+    fn dealloc_contiguous(&mut self, n: usize, count: usize);
+
+    /// Number of frames currently available for allocation.
+    fn free_frames(&self) -> usize;
+
+    /// Allocates a single frame, or returns `None` once physical memory is depleted.
+    fn alloc(&mut self) -> Option<usize> {
+        self.alloc_contiguous(1, 0)
+    }
+
+    fn dealloc(&mut self, n: usize) {
+        self.dealloc_contiguous(n, 1)
+    }
+}
+
+#[cfg(any(
+    all(feature = "frame-allocator-segment-tree", feature = "frame-allocator-bitmap"),
+    all(feature = "frame-allocator-segment-tree", feature = "frame-allocator-buddy"),
+    all(feature = "frame-allocator-bitmap", feature = "frame-allocator-buddy"),
+    not(any(
+        feature = "frame-allocator-segment-tree",
+        feature = "frame-allocator-bitmap",
+        feature = "frame-allocator-buddy",
+    )),
+))]
+compile_error!("select exactly one `frame-allocator-*` feature");
+
+#[cfg(feature = "frame-allocator-segment-tree")]
+pub type FrameAllocatorImpl = SegmentTreeAllocator;
+#[cfg(feature = "frame-allocator-bitmap")]
+pub type FrameAllocatorImpl = BitmapAllocator;
+#[cfg(feature = "frame-allocator-buddy")]
+pub type FrameAllocatorImpl = BuddyFrameAllocator<32>;
+
+pub static FRAME_ALLOCATOR: Mutex<FrameAllocatorImpl> = Mutex::new(FrameAllocatorImpl::new());
+
+pub static FRAME_REF_COUNTS: Mutex<FrameRefCounts> = Mutex::new(FrameRefCounts::new());
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consts::PAGE_SIZE;
+    use alloc::vec::Vec;
+    use proptest::prelude::*;
+
+    /// Operation on a frame allocator: allocate `count` frames aligned to `1 << align_log2`,
+    /// or free the allocation picked by `pick`.
+    #[derive(Debug, Clone)]
+    enum Op {
+        Alloc { count: usize, align_log2: usize },
+        Free { pick: usize },
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            3 => (1usize..40, 0usize..6).prop_map(|(count, align_log2)| Op::Alloc { count, align_log2 }),
+            2 => any::<usize>().prop_map(|pick| Op::Free { pick }),
+        ]
+    }
+
+    fn metadata<A: PhysFrameAllocator>(l: usize, r: usize) -> &'static mut [u8] {
+        let words = alloc::vec![0u64; A::metadata_size(l, r).div_ceil(8)].leak();
+        unsafe { core::slice::from_raw_parts_mut(words.as_mut_ptr() as *mut u8, words.len() * 8) }
+    }
+
+    /// Replays `ops` against the allocator and a plain `Vec<bool>` of free pages. A run
+    /// aligned to at least `block_align` only has to be found if a whole naturally aligned
+    /// block of at least its alignment and rounded-up size is free, as in a buddy allocator.
+    fn check_against_model<A: PhysFrameAllocator>(
+        mut allocator: A,
+        block_align: usize,
+        l: usize,
+        r: usize,
+        holes: &[(usize, usize)],
+        ops: &[Op],
+    ) -> Result<(), TestCaseError> {
+        allocator.init(l, r, metadata::<A>(l, r));
+        let mut free = alloc::vec![true; r - l];
+        for &(start, len) in holes {
+            let start = l + start % (r - l);
+            let end = (start + len).min(r);
+            for n in start..end {
+                free[n - l] = false;
+            }
+        }
+        // Pages start out allocated; hand back everything outside the holes.
+        for n in l..r {
+            if free[n - l] {
+                allocator.dealloc(n);
+            }
+        }
+        let mut live: Vec<(usize, usize)> = Vec::new();
+        for op in ops {
+            match *op {
+                Op::Alloc { count, align_log2 } => {
+                    let align = 1 << align_log2;
+                    let block = align.max(count.next_power_of_two());
+                    let (needed, needed_align) = if align >= block_align { (block, block) } else { (count, align) };
+                    let fits = (l..r).filter(|n| n % needed_align == 0).any(|n| {
+                        n + needed <= r && (n..n + needed).all(|m| free[m - l])
+                    });
+                    match allocator.alloc_contiguous(count, align_log2) {
+                        Some(n) => {
+                            prop_assert_eq!(n % align, 0);
+                            prop_assert!(n >= l && n + count <= r);
+                            for m in n..n + count {
+                                prop_assert!(free[m - l], "frame {:#x} handed out twice", m);
+                                free[m - l] = false;
+                            }
+                            live.push((n, count));
+                        }
+                        None => prop_assert!(!fits, "missed a free run of {} frames", count),
+                    }
+                }
+                Op::Free { pick } => {
+                    if live.is_empty() {
+                        continue;
+                    }
+                    let (n, count) = live.swap_remove(pick % live.len());
+                    allocator.dealloc_contiguous(n, count);
+                    for m in n..n + count {
+                        free[m - l] = true;
+                    }
+                }
+            }
+            prop_assert_eq!(allocator.free_frames(), free.iter().filter(|&&f| f).count());
+        }
+        Ok(())
+    }
+
+    proptest! {
+        #[test]
+        fn segment_tree_matches_model(
+            l in 0x80000usize..0x80100,
+            len in 1usize..600,
+            holes in prop::collection::vec((any::<usize>(), 0usize..50), 0..4),
+            ops in prop::collection::vec(op(), 1..200),
+        ) {
+            check_against_model(SegmentTreeAllocator::new(), 2, l, l + len, &holes, &ops)?;
+        }
+
+        #[test]
+        fn bitmap_matches_model(
+            l in 0x80000usize..0x80100,
+            len in 1usize..600,
+            holes in prop::collection::vec((any::<usize>(), 0usize..50), 0..4),
+            ops in prop::collection::vec(op(), 1..200),
+        ) {
+            check_against_model(BitmapAllocator::new(), usize::MAX, l, l + len, &holes, &ops)?;
+        }
+
+        #[test]
+        fn buddy_matches_model(
+            l in 0x80000usize..0x80100,
+            len in 1usize..600,
+            holes in prop::collection::vec((any::<usize>(), 0usize..50), 0..4),
+            ops in prop::collection::vec(op(), 1..200),
+        ) {
+            // The free lists live in the free frames, so back the pages with host memory.
+            let mut frames = alloc::vec![0u64; len * PAGE_SIZE / 8];
+            let window = (frames.as_mut_ptr() as usize).wrapping_sub(l * PAGE_SIZE);
+            let allocator = BuddyFrameAllocator::<32>::with_window(window);
+            check_against_model(allocator, 1, l, l + len, &holes, &ops)?;
+        }
+    }
+}
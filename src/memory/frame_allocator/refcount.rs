@@ -0,0 +1,88 @@
+/// How many page table entries share each frame, for copy-on-write.
+///
+/// A frame is owned by whoever allocated it until `share` is called on it. Only the
+/// additional references are counted, so frames that are never shared cost nothing beyond
+/// their slot. Like the frame allocators, the counts live in memory handed over by `init`.
+pub struct FrameRefCounts {
+    l: usize,
+    counts: &'static mut [u16],
+}
+
+impl FrameRefCounts {
+    pub const fn new() -> Self {
+        FrameRefCounts {
+            l: 0,
+            counts: &mut [],
+        }
+    }
+
+    /// Bytes of bookkeeping needed for page numbers `[l, r)`.
+    pub fn metadata_size(l: usize, r: usize) -> usize {
+        (r - l) * core::mem::size_of::<u16>()
+    }
+
+    /// Takes over page numbers `[l, r)`, none of them shared.
+    pub fn init(&mut self, l: usize, r: usize, metadata: &'static mut [u8]) {
+        assert!(metadata.len() >= Self::metadata_size(l, r));
+        let counts = unsafe { core::slice::from_raw_parts_mut(metadata.as_mut_ptr() as *mut u16, r - l) };
+        counts.fill(0);
+        self.l = l;
+        self.counts = counts;
+    }
+
+    fn slot(&mut self, n: usize) -> &mut u16 {
+        &mut self.counts[n - self.l]
+    }
+
+    /// Number of references to the allocated frame `n`.
+    pub fn refs(&mut self, n: usize) -> usize {
+        *self.slot(n) as usize + 1
+    }
+
+    /// Adds a reference to the allocated frame `n`.
+    pub fn share(&mut self, n: usize) {
+        let count = self.slot(n);
+        *count = count.checked_add(1).expect("frame shared too many times");
+    }
+
+    /// Drops a reference to frame `n`, returning whether it was the last one and the frame
+    /// must be freed.
+    pub fn unshare(&mut self, n: usize) -> bool {
+        let count = self.slot(n);
+        if *count == 0 {
+            return true;
+        }
+        *count -= 1;
+        false
+    }
+}
+
+impl Default for FrameRefCounts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn last_reference_frees_the_frame() {
+        let words = alloc::vec![u16::MAX; 0x10].leak();
+        let metadata = unsafe { core::slice::from_raw_parts_mut(words.as_mut_ptr() as *mut u8, 0x20) };
+        let mut refs = FrameRefCounts::new();
+        refs.init(0x80000, 0x80010, metadata);
+        assert_eq!(refs.refs(0x8000f), 1);
+        assert!(refs.unshare(0x8000f));
+
+        refs.share(0x80003);
+        refs.share(0x80003);
+        assert_eq!(refs.refs(0x80003), 3);
+        assert!(!refs.unshare(0x80003));
+        assert!(!refs.unshare(0x80003));
+        assert_eq!(refs.refs(0x80003), 1);
+        assert!(refs.unshare(0x80003));
+        assert_eq!(refs.refs(0x80004), 1);
+    }
+}
@@ -0,0 +1,183 @@
+use super::PhysFrameAllocator;
+use core::cmp::max;
+use core::mem::size_of;
+
+/// Segment tree over physical page numbers.
+///
+/// Every node keeps the length of the longest free run inside its range together with the
+/// free runs touching its left and right borders, plus the size of the largest wholly free
+/// node below it. Leaf 0 is a page number aligned to the number of leaves, so every node
+/// covers a naturally aligned block. Single frames and unaligned runs are found in
+/// O(log n); an aligned run is carved out of a wholly free node of at least its alignment
+/// and rounded-up size, which is also found along a single path.
+pub struct SegmentTreeAllocator {
+    longest: &'static mut [u32],
+    prefix: &'static mut [u32],
+    suffix: &'static mut [u32],
+    block: &'static mut [u32],
+    m: usize,
+    l: usize,
+    r: usize,
+    offset: usize,
+    free: usize,
+}
+
+impl SegmentTreeAllocator {
+    pub const fn new() -> Self {
+        SegmentTreeAllocator {
+            longest: &mut [],
+            prefix: &mut [],
+            suffix: &mut [],
+            block: &mut [],
+            m: 0,
+            l: 0,
+            r: 0,
+            offset: 0,
+            free: 0,
+        }
+    }
+
+    /// Number of leaves needed so that one window aligned to it covers pages `[l, r)`.
+    fn leaves(l: usize, r: usize) -> usize {
+        let mut m = (r - l).next_power_of_two();
+        while (l & !(m - 1)) + m < r {
+            m <<= 1;
+        }
+        m
+    }
+
+    /// Length of the range covered by node `p`.
+    fn len(&self, p: usize) -> usize {
+        self.m >> (usize::BITS - 1 - p.leading_zeros())
+    }
+
+    fn set_leaf(&mut self, p: usize, free: bool) {
+        let v = free as u32;
+        self.longest[p] = v;
+        self.prefix[p] = v;
+        self.suffix[p] = v;
+        self.block[p] = v;
+    }
+
+    fn pull(&mut self, p: usize) {
+        let (l, r) = (p << 1, (p << 1) | 1);
+        let half = self.len(l) as u32;
+        self.prefix[p] = if self.prefix[l] == half { half + self.prefix[r] } else { self.prefix[l] };
+        self.suffix[p] = if self.suffix[r] == half { half + self.suffix[l] } else { self.suffix[r] };
+        self.longest[p] = max(max(self.longest[l], self.longest[r]), self.suffix[l] + self.prefix[r]);
+        self.block[p] = if self.longest[p] == 2 * half { 2 * half } else { max(self.block[l], self.block[r]) };
+    }
+
+    /// Marks leaves `[l, r)` as free or used and refreshes their ancestors level by level.
+    fn update(&mut self, l: usize, r: usize, free: bool) {
+        for i in l..r { self.set_leaf(self.m + i, free); }
+        let (mut a, mut b) = ((l + self.m) >> 1, (r - 1 + self.m) >> 1);
+        while a > 0 {
+            for p in a..=b { self.pull(p); }
+            a >>= 1;
+            b >>= 1;
+        }
+    }
+
+    /// Finds a free run of `count` leaves inside node `p`, whose range starts at leaf `lo`.
+    ///
+    /// A free run that crosses the middle of a node is only visible from that node, so it
+    /// is tried after the left child and before the right one.
+    fn search(&self, p: usize, lo: usize, count: usize) -> Option<usize> {
+        if (self.longest[p] as usize) < count {
+            return None;
+        }
+        let len = self.len(p);
+        if self.longest[p] as usize == len {
+            return Some(lo);
+        }
+        let (l, r) = (p << 1, (p << 1) | 1);
+        let mid = lo + (len >> 1);
+        if self.longest[l] as usize >= count {
+            self.search(l, lo, count)
+        } else if (self.suffix[l] + self.prefix[r]) as usize >= count {
+            Some(mid - self.suffix[l] as usize)
+        } else {
+            self.search(r, mid, count)
+        }
+    }
+
+    /// Finds a wholly free node of at least `size` leaves inside node `p`, whose range
+    /// starts at leaf `lo`.
+    fn search_block(&self, p: usize, lo: usize, size: usize) -> Option<usize> {
+        if (self.block[p] as usize) < size {
+            return None;
+        }
+        let len = self.len(p);
+        if self.block[p] as usize == len {
+            return Some(lo);
+        }
+        if self.block[p << 1] as usize >= size {
+            self.search_block(p << 1, lo, size)
+        } else {
+            self.search_block((p << 1) | 1, lo + (len >> 1), size)
+        }
+    }
+}
+
+impl Default for SegmentTreeAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PhysFrameAllocator for SegmentTreeAllocator {
+    fn name(&self) -> &'static str {
+        "segment tree"
+    }
+
+    fn metadata_size(l: usize, r: usize) -> usize {
+        4 * (Self::leaves(l, r) << 1) * size_of::<u32>()
+    }
+
+    fn init(&mut self, l: usize, r: usize, metadata: &'static mut [u8]) {
+        self.m = Self::leaves(l, r);
+        self.offset = l & !(self.m - 1);
+        self.l = l;
+        self.r = r;
+        self.free = 0;
+        let nodes = self.m << 1;
+        let (_, words, _) = unsafe { metadata.align_to_mut::<u32>() };
+        let (longest, rest) = words.split_at_mut(nodes);
+        let (prefix, rest) = rest.split_at_mut(nodes);
+        let (suffix, rest) = rest.split_at_mut(nodes);
+        self.longest = longest;
+        self.prefix = prefix;
+        self.suffix = suffix;
+        self.block = &mut rest[..nodes];
+        for i in 0..self.m { self.set_leaf(self.m + i, false); }
+        for i in (1..self.m).rev() { self.pull(i); }
+    }
+
+    fn alloc_contiguous(&mut self, count: usize, align_log2: usize) -> Option<usize> {
+        assert!(count > 0, "cannot allocate zero frames");
+        let align = 1 << align_log2;
+        let start = if align == 1 {
+            self.search(1, 0, count)?
+        } else {
+            self.search_block(1, 0, max(align, count.next_power_of_two()))?
+        };
+        self.update(start, start + count, false);
+        self.free -= count;
+        Some(start + self.offset)
+    }
+
+    fn dealloc_contiguous(&mut self, n: usize, count: usize) {
+        assert!(n >= self.l && n + count <= self.r, "frame {:#x} is out of range", n);
+        let start = n - self.offset;
+        for p in (start..start + count).map(|i| i + self.m) {
+            assert!(self.longest[p] == 0, "frame {:#x} is not allocated", p - self.m + self.offset);
+        }
+        self.update(start, start + count, true);
+        self.free += count;
+    }
+
+    fn free_frames(&self) -> usize {
+        self.free
+    }
+}
@@ -0,0 +1,52 @@
+use crate::address::*;
+use crate::consts::PAGE_SIZE;
+use crate::paging::access_pa_via_va;
+use super::{alloc_frame, dealloc_frame};
+use core::ops::{Deref, DerefMut};
+
+/// An owned physical frame.
+///
+/// The frame is zeroed when it is allocated, its contents are reachable through the
+/// linear mapping, and it is returned to the frame allocator when the tracker is dropped.
+#[derive(Debug)]
+pub struct FrameTracker(Frame);
+
+impl FrameTracker {
+    pub fn new() -> Option<Self> {
+        let mut tracker = FrameTracker(alloc_frame()?);
+        tracker.fill(0);
+        Some(tracker)
+    }
+
+    pub fn frame(&self) -> Frame {
+        self.0
+    }
+
+    pub fn start_address(&self) -> PhysAddr {
+        self.0.start_address()
+    }
+
+    pub fn number(&self) -> usize {
+        self.0.number()
+    }
+}
+
+impl Deref for FrameTracker {
+    type Target = [u8; PAGE_SIZE];
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*(access_pa_via_va(self.start_address().as_usize()) as *const Self::Target) }
+    }
+}
+
+impl DerefMut for FrameTracker {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *(access_pa_via_va(self.start_address().as_usize()) as *mut Self::Target) }
+    }
+}
+
+impl Drop for FrameTracker {
+    fn drop(&mut self) {
+        dealloc_frame(self.0);
+    }
+}
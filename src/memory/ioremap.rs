@@ -0,0 +1,40 @@
+//! Mapping device registers into the kernel address space.
+
+use crate::address::*;
+use crate::consts::*;
+use super::memory_set::attr::MemoryAttr;
+use super::memory_set::handler::Device;
+use super::memory_set::KERNEL_MEMORY_SET;
+
+const _: () = assert!(KERNEL_SHARED_BASE <= IOREMAP_BASE && IOREMAP_END <= KERNEL_SHARED_END);
+
+/// Maps the device registers at physical `[pa, pa + len)` into `[IOREMAP_BASE, IOREMAP_END)`,
+/// readable, writable and never executable, and returns the address `pa` ends up at. The
+/// window is shared by every address space, so the registers are reachable whichever one is
+/// active. Fails if the window or the frames for page tables run out.
+pub fn ioremap(pa: usize, len: usize) -> Option<VirtAddr> {
+    if len == 0 {
+        return None;
+    }
+    let start_pa = pa & !(PAGE_SIZE - 1);
+    let end_pa = pa.checked_add(len)?.checked_next_multiple_of(PAGE_SIZE)?;
+    let mut memory_set = KERNEL_MEMORY_SET.lock();
+    let memory_set = memory_set.as_mut()?;
+    let start = memory_set.find_free_area_in(end_pa - start_pa, IOREMAP_BASE, IOREMAP_END)?;
+    let end = start + (end_pa - start_pa);
+    memory_set
+        .push(start, end, MemoryAttr::new(), Device::new(start.wrapping_sub(start_pa)))
+        .ok()?;
+    Some(VirtAddr::new(start + (pa - start_pa)))
+}
+
+/// Unmaps the device registers `ioremap` mapped at `va`.
+pub fn iounmap(va: VirtAddr) {
+    let va = va.as_usize();
+    assert!((IOREMAP_BASE..IOREMAP_END).contains(&va), "{:#x} was not mapped by ioremap", va);
+    let mut memory_set = KERNEL_MEMORY_SET.lock();
+    let memory_set = memory_set.as_mut().unwrap();
+    let area = memory_set.find_area(va).expect("iounmap of an address that is not mapped");
+    let (start, end) = (area.start(), area.end());
+    memory_set.remove(start, end);
+}
@@ -0,0 +1,82 @@
+//! Kernel stacks with a guard page underneath.
+//!
+//! `[KERNEL_STACK_BASE, KERNEL_STACK_END)` is cut into slots of `KERNEL_STACK_SLOT_SIZE`
+//! bytes. The lowest page of each slot is never mapped, so running off the bottom of the
+//! stack above it faults instead of overwriting whatever lies below.
+//!
+//! The region lies in `[KERNEL_SHARED_BASE, KERNEL_SHARED_END)`, whose page table every
+//! address space links, so a stack mapped or unmapped in the kernel address space is mapped
+//! or unmapped in all of them at once, and a slot can be reused as soon as its stack is gone.
+
+use crate::consts::*;
+use super::memory_set::attr::MemoryAttr;
+use super::memory_set::handler::ByFrame;
+use super::memory_set::KERNEL_MEMORY_SET;
+
+const _: () = assert!(KERNEL_SHARED_BASE <= KERNEL_STACK_BASE && KERNEL_STACK_END <= KERNEL_SHARED_END);
+const _: () = assert!((KERNEL_STACK_END - KERNEL_STACK_BASE).is_power_of_two());
+const _: () = assert!(KERNEL_STACK_SLOT_SIZE.is_power_of_two());
+
+/// A kernel stack mapped in every address space, unmapped again when dropped.
+pub struct KernelStack {
+    bottom: usize,
+}
+
+impl KernelStack {
+    /// Maps a stack in the first free slot. The whole stack is backed up front, since the
+    /// trap entry cannot take a page fault while saving registers onto it.
+    pub fn new() -> Option<Self> {
+        let mut memory_set = KERNEL_MEMORY_SET.lock();
+        let memory_set = memory_set.as_mut()?;
+        let bottom = (KERNEL_STACK_BASE..KERNEL_STACK_END)
+            .step_by(KERNEL_STACK_SLOT_SIZE)
+            .map(|slot| slot + PAGE_SIZE)
+            .find(|&bottom| memory_set.test_free_area(bottom, bottom + KERNEL_STACK_SIZE))?;
+        memory_set
+            .push(bottom, bottom + KERNEL_STACK_SIZE, MemoryAttr::new(), ByFrame::new())
+            .ok()?;
+        Some(KernelStack { bottom })
+    }
+
+    /// The lowest address of the stack; the guard page ends here.
+    pub fn bottom(&self) -> usize {
+        self.bottom
+    }
+
+    /// The initial stack pointer.
+    pub fn top(&self) -> usize {
+        self.bottom + KERNEL_STACK_SIZE
+    }
+
+    /// Switches to this stack for good and calls `f` on it. The current stack is abandoned
+    /// and this one is never freed.
+    pub fn run(self, f: extern "C" fn() -> !) -> ! {
+        let top = self.top();
+        core::mem::forget(self);
+        unsafe {
+            core::arch::asm!(
+                "mv sp, {top}",
+                "jr {f}",
+                top = in(reg) top,
+                f = in(reg) f,
+                options(noreturn)
+            )
+        }
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        KERNEL_MEMORY_SET
+            .lock()
+            .as_mut()
+            .unwrap()
+            .remove(self.bottom, self.top());
+    }
+}
+
+/// Whether `va` is in the guard page below some kernel stack.
+pub fn is_guard_page(va: usize) -> bool {
+    (KERNEL_STACK_BASE..KERNEL_STACK_END).contains(&va)
+        && (va - KERNEL_STACK_BASE) % KERNEL_STACK_SLOT_SIZE < PAGE_SIZE
+}
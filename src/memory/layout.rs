@@ -0,0 +1,91 @@
+use core::fmt;
+
+pub const MAX_MEMORY_REGIONS: usize = 16;
+
+/// A physical address range `[start, end)`.
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub struct MemoryRegion {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl MemoryRegion {
+    pub fn new(start: usize, end: usize) -> Self {
+        MemoryRegion { start, end }
+    }
+}
+
+impl fmt::Debug for MemoryRegion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{:#x}, {:#x})", self.start, self.end)
+    }
+}
+
+/// RAM ranges and the reserved ranges carved out of them, as discovered at boot.
+#[derive(Debug, Clone)]
+pub struct MemoryLayout {
+    memory: [MemoryRegion; MAX_MEMORY_REGIONS],
+    memory_count: usize,
+    reserved: [MemoryRegion; MAX_MEMORY_REGIONS],
+    reserved_count: usize,
+}
+
+impl MemoryLayout {
+    pub const fn new() -> Self {
+        MemoryLayout {
+            memory: [MemoryRegion { start: 0, end: 0 }; MAX_MEMORY_REGIONS],
+            memory_count: 0,
+            reserved: [MemoryRegion { start: 0, end: 0 }; MAX_MEMORY_REGIONS],
+            reserved_count: 0,
+        }
+    }
+
+    pub fn add_memory(&mut self, start: usize, end: usize) {
+        assert!(self.memory_count < MAX_MEMORY_REGIONS, "too many memory regions!");
+        self.memory[self.memory_count] = MemoryRegion::new(start, end);
+        self.memory_count += 1;
+        self.memory[..self.memory_count].sort_unstable_by_key(|r| r.start);
+    }
+
+    pub fn add_reserved(&mut self, start: usize, end: usize) {
+        assert!(self.reserved_count < MAX_MEMORY_REGIONS, "too many reserved regions!");
+        self.reserved[self.reserved_count] = MemoryRegion::new(start, end);
+        self.reserved_count += 1;
+        self.reserved[..self.reserved_count].sort_unstable_by_key(|r| r.start);
+    }
+
+    /// RAM regions sorted by start address.
+    pub fn memory(&self) -> &[MemoryRegion] {
+        &self.memory[..self.memory_count]
+    }
+
+    /// Reserved regions sorted by start address. They may overlap each other.
+    pub fn reserved(&self) -> &[MemoryRegion] {
+        &self.reserved[..self.reserved_count]
+    }
+
+    /// Calls `f` on every piece of RAM not covered by a reserved region, in address order.
+    pub fn for_each_usable(&self, mut f: impl FnMut(MemoryRegion)) {
+        for region in self.memory() {
+            let mut cursor = region.start;
+            for reserved in self.reserved() {
+                if reserved.start >= region.end {
+                    break;
+                }
+                if reserved.start > cursor {
+                    f(MemoryRegion::new(cursor, reserved.start));
+                }
+                cursor = cursor.max(reserved.end);
+            }
+            if cursor < region.end {
+                f(MemoryRegion::new(cursor, region.end));
+            }
+        }
+    }
+}
+
+impl Default for MemoryLayout {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -113,4 +113,40 @@ impl<'a> Iterator for IterMut<'a> {
             Some(res)
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+    use proptest::prelude::*;
+
+    #[test]
+    fn push_pop_is_lifo() {
+        let mut nodes = [0usize; 3];
+        let mut list = LinkedList::new();
+        assert!(list.is_empty());
+        for node in nodes.iter_mut() {
+            unsafe { list.push(node) };
+        }
+        let popped: Vec<_> = core::iter::from_fn(|| list.pop()).collect();
+        let expected: Vec<_> = nodes.iter_mut().rev().map(|node| node as *mut usize).collect();
+        assert_eq!(popped, expected);
+        assert!(list.is_empty());
+    }
+
+    proptest! {
+        #[test]
+        fn pop_through_iter_mut_unlinks_one_node(len in 1usize..32, victim in 0usize..32) {
+            let victim = victim % len;
+            let mut nodes = alloc::vec![0usize; len];
+            let mut list = LinkedList::new();
+            for node in nodes.iter_mut() {
+                unsafe { list.push(node) };
+            }
+            let expected: Vec<_> = list.iter().enumerate().filter(|&(i, _)| i != victim).map(|(_, p)| p).collect();
+            let removed = list.iter_mut().nth(victim).unwrap().pop();
+            prop_assert_eq!(removed, &mut nodes[len - 1 - victim] as *mut usize);
+            prop_assert_eq!(list.iter().collect::<Vec<_>>(), expected);
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use super::attr::MemoryAttr;
+use super::handler::MemoryHandler;
+use crate::consts::PAGE_SIZE;
+use crate::page_table::MapToError;
+use crate::paging::{PageRange, PageTableImpl};
+use alloc::boxed::Box;
+
+/// A contiguous virtual range `[start, end)` mapped through its handler.
+#[derive(Debug, Clone)]
+pub struct MemoryArea {
+    start: usize,
+    end: usize,
+    handler: Box<dyn MemoryHandler>,
+    attr: MemoryAttr,
+}
+
+impl MemoryArea {
+    pub fn new(start: usize, end: usize, handler: Box<dyn MemoryHandler>, attr: MemoryAttr) -> Self {
+        assert!(start < end, "empty memory area [{:#x}, {:#x})", start, end);
+        MemoryArea {
+            start,
+            end,
+            handler,
+            attr,
+        }
+    }
+
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    pub fn attr(&self) -> &MemoryAttr {
+        &self.attr
+    }
+
+    pub fn contains(&self, va: usize) -> bool {
+        self.start <= va && va < self.end
+    }
+
+    pub fn is_overlap_with(&self, start: usize, end: usize) -> bool {
+        let p1 = self.start / PAGE_SIZE;
+        let p2 = (self.end - 1) / PAGE_SIZE + 1;
+        let p3 = start / PAGE_SIZE;
+        let p4 = (end - 1) / PAGE_SIZE + 1;
+        !(p1 >= p4 || p3 >= p2)
+    }
+
+    pub fn map(&self, pt: &mut PageTableImpl) -> Result<(), MapToError> {
+        self.handler.map_area(pt, self.start, self.end, &self.attr)
+    }
+
+    pub fn unmap(&self, pt: &mut PageTableImpl) {
+        self.handler.unmap_area(pt, self.start, self.end);
+    }
+
+    /// Splits the area at `va`, which must lie strictly inside it, without touching the
+    /// page table.
+    pub fn split(self, va: usize) -> (MemoryArea, MemoryArea) {
+        assert!(self.start < va && va < self.end && va.is_multiple_of(PAGE_SIZE));
+        let upper = MemoryArea::new(va, self.end, self.handler.clone(), self.attr);
+        (MemoryArea::new(self.start, va, self.handler, self.attr), upper)
+    }
+
+    /// Changes the permissions of the area and of every page it has mapped.
+    pub fn protect(&mut self, pt: &mut PageTableImpl, attr: MemoryAttr) {
+        self.attr = attr;
+        pt.protect_range(PageRange::new(self.start, self.end), &attr);
+    }
+
+    /// Maps the area of `src` into `dst` for a copy-on-write clone.
+    pub fn clone_cow(&self, src: &mut PageTableImpl, dst: &mut PageTableImpl) -> Result<(), MapToError> {
+        self.handler.clone_cow(src, dst, self.start, self.end, &self.attr)
+    }
+
+    /// Lets the handler resolve a page fault at `va`, which must lie in the area.
+    pub fn handle_page_fault(&self, pt: &mut PageTableImpl, va: usize) -> bool {
+        self.handler.handle_page_fault(pt, va, &self.attr)
+    }
+}
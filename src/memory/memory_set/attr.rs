@@ -0,0 +1,71 @@
+use crate::page_table::PageTableFlags;
+use crate::paging::PageEntry;
+
+/// Access permissions of a memory area, applied to every page it maps.
+///
+/// Pages are always readable; writable unless `set_readonly` is used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryAttr {
+    user: bool,
+    readonly: bool,
+    execute: bool,
+}
+
+impl MemoryAttr {
+    pub fn new() -> Self {
+        MemoryAttr {
+            user: false,
+            readonly: false,
+            execute: false,
+        }
+    }
+
+    pub fn set_user(mut self) -> Self {
+        self.user = true;
+        self
+    }
+
+    pub fn set_readonly(mut self) -> Self {
+        self.readonly = true;
+        self
+    }
+
+    pub fn set_execute(mut self) -> Self {
+        self.execute = true;
+        self
+    }
+
+    pub fn user(&self) -> bool {
+        self.user
+    }
+
+    pub fn readonly(&self) -> bool {
+        self.readonly
+    }
+
+    pub fn execute(&self) -> bool {
+        self.execute
+    }
+
+    /// The flags of a leaf entry mapping a page with these permissions.
+    pub fn flags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::VALID | PageTableFlags::READABLE;
+        flags.set(PageTableFlags::USER, self.user);
+        flags.set(PageTableFlags::WRITABLE, !self.readonly);
+        flags.set(PageTableFlags::EXECUTABLE, self.execute);
+        flags
+    }
+
+    pub fn apply(&self, entry: &mut PageEntry) {
+        entry.set_present(true);
+        entry.set_user(self.user);
+        entry.set_writable(!self.readonly);
+        entry.set_execute(self.execute);
+    }
+}
+
+impl Default for MemoryAttr {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,402 @@
+use super::attr::MemoryAttr;
+use crate::alloc_frame;
+use crate::consts::PAGE_SIZE;
+use crate::dealloc_frame;
+use crate::release_frame;
+use crate::swap;
+use crate::page_table::MapToError;
+use crate::paging::{access_pa_via_va, PageRange, PageTableImpl};
+use crate::address::*;
+use super::mmap::SharedMemory;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::fmt::Debug;
+
+/// Decides where the pages of a memory area come from.
+pub trait MemoryHandler: Debug + Send + Sync + 'static {
+    fn box_clone(&self) -> Box<dyn MemoryHandler>;
+
+    /// Maps the page at `va` with the permissions in `attr`.
+    fn map(&self, pt: &mut PageTableImpl, va: usize, attr: &MemoryAttr) -> Result<(), MapToError>;
+
+    /// Unmaps the page at `va`, releasing whatever backed it.
+    fn unmap(&self, pt: &mut PageTableImpl, va: usize);
+
+    /// Maps every page of `[start, end)`. If one of them fails, the pages mapped so far are
+    /// unmapped again.
+    fn map_area(&self, pt: &mut PageTableImpl, start: usize, end: usize, attr: &MemoryAttr) -> Result<(), MapToError> {
+        for page in PageRange::new(start, end) {
+            if let Err(e) = self.map(pt, page, attr) {
+                for mapped in PageRange::new(start, page) {
+                    self.unmap(pt, mapped);
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn unmap_area(&self, pt: &mut PageTableImpl, start: usize, end: usize) {
+        for page in PageRange::new(start, end) {
+            self.unmap(pt, page);
+        }
+    }
+
+    /// Maps `[start, end)` of `src` into `dst` for a copy-on-write clone of `src`. By default
+    /// the clone gets its own mapping, as `map_area` makes it.
+    fn clone_cow(
+        &self,
+        _src: &mut PageTableImpl,
+        dst: &mut PageTableImpl,
+        start: usize,
+        end: usize,
+        attr: &MemoryAttr,
+    ) -> Result<(), MapToError> {
+        self.map_area(dst, start, end, attr)
+    }
+
+    /// Tries to resolve a page fault at `va`, returning whether the access can be retried.
+    fn handle_page_fault(&self, _pt: &mut PageTableImpl, _va: usize, _attr: &MemoryAttr) -> bool {
+        false
+    }
+}
+
+impl Clone for Box<dyn MemoryHandler> {
+    fn clone(&self) -> Box<dyn MemoryHandler> {
+        self.box_clone()
+    }
+}
+
+/// Maps `va` to the physical address `va - offset`.
+#[derive(Debug, Clone)]
+pub struct Linear {
+    offset: usize,
+}
+
+impl Linear {
+    pub fn new(offset: usize) -> Self {
+        Linear { offset }
+    }
+}
+
+impl MemoryHandler for Linear {
+    fn box_clone(&self) -> Box<dyn MemoryHandler> {
+        Box::new(self.clone())
+    }
+
+    fn map(&self, pt: &mut PageTableImpl, va: usize, attr: &MemoryAttr) -> Result<(), MapToError> {
+        attr.apply(pt.map(va, va.wrapping_sub(self.offset))?);
+        Ok(())
+    }
+
+    fn unmap(&self, pt: &mut PageTableImpl, va: usize) {
+        pt.unmap(va);
+    }
+
+    /// Uses huge pages wherever the alignment allows.
+    fn map_area(&self, pt: &mut PageTableImpl, start: usize, end: usize, attr: &MemoryAttr) -> Result<(), MapToError> {
+        for (va, size) in PageTableImpl::linear_pages(start, end, self.offset) {
+            if let Err(e) = pt.map_huge(va, va.wrapping_sub(self.offset), size).map(|entry| attr.apply(entry)) {
+                self.unmap_area(pt, start, va);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn unmap_area(&self, pt: &mut PageTableImpl, start: usize, end: usize) {
+        for (va, _) in PageTableImpl::linear_pages(start, end, self.offset) {
+            pt.unmap(va);
+        }
+    }
+}
+
+/// Backs every page with a freshly allocated, zeroed frame that is freed on unmap.
+#[derive(Debug, Clone)]
+pub struct ByFrame;
+
+impl ByFrame {
+    pub fn new() -> Self {
+        ByFrame
+    }
+}
+
+impl Default for ByFrame {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryHandler for ByFrame {
+    fn box_clone(&self) -> Box<dyn MemoryHandler> {
+        Box::new(self.clone())
+    }
+
+    fn map(&self, pt: &mut PageTableImpl, va: usize, attr: &MemoryAttr) -> Result<(), MapToError> {
+        let frame = alloc_frame().ok_or(MapToError::FrameAllocationFailed)?;
+        let pa = frame.start_address().as_usize();
+        unsafe { core::ptr::write_bytes(access_pa_via_va(pa) as *mut u8, 0, PAGE_SIZE) };
+        match pt.map(va, pa) {
+            Ok(entry) => {
+                attr.apply(entry);
+                Ok(())
+            }
+            Err(e) => {
+                dealloc_frame(frame);
+                Err(e)
+            }
+        }
+    }
+
+    fn unmap(&self, pt: &mut PageTableImpl, va: usize) {
+        let pa = pt.get_entry(va).expect("unmapping a page that is not mapped").target();
+        pt.unmap(va);
+        release_frame(Frame::of_addr(PhysAddr::new(pa)));
+    }
+
+    /// Shares the frames copy-on-write.
+    fn clone_cow(
+        &self,
+        src: &mut PageTableImpl,
+        dst: &mut PageTableImpl,
+        start: usize,
+        end: usize,
+        _attr: &MemoryAttr,
+    ) -> Result<(), MapToError> {
+        src.clone_cow(dst, start, end)
+    }
+}
+
+/// Like `ByFrame`, but a page only gets its frame when it is first touched: `map` leaves
+/// it unmapped and the page fault handler maps it.
+#[derive(Debug, Clone)]
+pub struct Delay;
+
+impl Delay {
+    pub fn new() -> Self {
+        Delay
+    }
+}
+
+impl Default for Delay {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryHandler for Delay {
+    fn box_clone(&self) -> Box<dyn MemoryHandler> {
+        Box::new(self.clone())
+    }
+
+    fn map(&self, _pt: &mut PageTableImpl, _va: usize, _attr: &MemoryAttr) -> Result<(), MapToError> {
+        Ok(())
+    }
+
+    fn unmap(&self, pt: &mut PageTableImpl, va: usize) {
+        if pt.get_entry(va).is_some_and(|entry| entry.present()) {
+            ByFrame.unmap(pt, va);
+        }
+    }
+
+    /// Shares the pages touched so far copy-on-write; the others stay lazy in both.
+    fn clone_cow(
+        &self,
+        src: &mut PageTableImpl,
+        dst: &mut PageTableImpl,
+        start: usize,
+        end: usize,
+        _attr: &MemoryAttr,
+    ) -> Result<(), MapToError> {
+        src.clone_cow(dst, start, end)
+    }
+
+    fn handle_page_fault(&self, pt: &mut PageTableImpl, va: usize, attr: &MemoryAttr) -> bool {
+        if pt.get_entry(va).is_some_and(|entry| entry.present()) {
+            // Already backed, so the access itself is not allowed.
+            return false;
+        }
+        ByFrame.map(pt, va & !(PAGE_SIZE - 1), attr).is_ok()
+    }
+}
+
+/// Like `Delay`, but the swap manager may swap the pages out when memory runs low.
+#[derive(Debug, Clone)]
+pub struct Swappable;
+
+impl Swappable {
+    pub fn new() -> Self {
+        Swappable
+    }
+}
+
+impl Default for Swappable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryHandler for Swappable {
+    fn box_clone(&self) -> Box<dyn MemoryHandler> {
+        Box::new(self.clone())
+    }
+
+    fn map(&self, _pt: &mut PageTableImpl, _va: usize, _attr: &MemoryAttr) -> Result<(), MapToError> {
+        Ok(())
+    }
+
+    fn unmap(&self, pt: &mut PageTableImpl, va: usize) {
+        match pt.get_entry(va) {
+            Some(entry) if entry.present() => {
+                swap::remove(entry);
+                ByFrame.unmap(pt, va);
+            }
+            Some(entry) if entry.swapped() => swap::free_slot(entry),
+            _ => {}
+        }
+    }
+
+    /// Swappable frames are not shared: the clone gets a copy of every page touched so far.
+    fn clone_cow(
+        &self,
+        src: &mut PageTableImpl,
+        dst: &mut PageTableImpl,
+        start: usize,
+        end: usize,
+        attr: &MemoryAttr,
+    ) -> Result<(), MapToError> {
+        for page in PageRange::new(start, end) {
+            if !src.get_entry(page).is_some_and(|entry| entry.present() || entry.swapped()) {
+                continue;
+            }
+            let result = alloc_frame().ok_or(MapToError::FrameAllocationFailed).and_then(|frame| {
+                let pa = frame.start_address().as_usize();
+                // Allocating may have swapped the source page out, so look at it only now.
+                let entry = src.get_entry(page).unwrap();
+                if entry.swapped() {
+                    swap::read_swapped(entry, pa);
+                } else {
+                    unsafe {
+                        core::ptr::copy_nonoverlapping(
+                            access_pa_via_va(entry.target()) as *const u8,
+                            access_pa_via_va(pa) as *mut u8,
+                            PAGE_SIZE,
+                        );
+                    }
+                }
+                match dst.map(page, pa) {
+                    Ok(entry) => {
+                        attr.apply(entry);
+                        entry.clear_dirty();
+                        unsafe { swap::insert(entry) };
+                        Ok(())
+                    }
+                    Err(e) => {
+                        dealloc_frame(frame);
+                        Err(e)
+                    }
+                }
+            });
+            if let Err(e) = result {
+                self.unmap_area(dst, start, page);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_page_fault(&self, pt: &mut PageTableImpl, va: usize, attr: &MemoryAttr) -> bool {
+        let va = va & !(PAGE_SIZE - 1);
+        match pt.get_entry(va) {
+            Some(entry) if entry.swapped() => return unsafe { swap::swap_in(entry) }.is_ok(),
+            // Already resident, so the access itself is not allowed.
+            Some(entry) if entry.present() => return false,
+            _ => {}
+        }
+        if ByFrame.map(pt, va, attr).is_err() {
+            return false;
+        }
+        // A fresh page starts out clean, like one just swapped in.
+        let entry = pt.get_entry(va).unwrap();
+        entry.clear_dirty();
+        unsafe { swap::insert(entry) };
+        true
+    }
+}
+
+/// Maps the frames of a `SharedMemory`, the first one at `start`. Every page table mapping
+/// it sees the same frames, and they are freed with the last mapping.
+#[derive(Debug, Clone)]
+pub struct Shared {
+    memory: Arc<SharedMemory>,
+    start: usize,
+}
+
+impl Shared {
+    pub fn new(memory: Arc<SharedMemory>, start: usize) -> Self {
+        Shared { memory, start }
+    }
+}
+
+impl MemoryHandler for Shared {
+    fn box_clone(&self) -> Box<dyn MemoryHandler> {
+        Box::new(self.clone())
+    }
+
+    fn map(&self, pt: &mut PageTableImpl, va: usize, attr: &MemoryAttr) -> Result<(), MapToError> {
+        let pa = self.memory.frame((va - self.start) / PAGE_SIZE).start_address().as_usize();
+        attr.apply(pt.map(va, pa)?);
+        Ok(())
+    }
+
+    fn unmap(&self, pt: &mut PageTableImpl, va: usize) {
+        pt.unmap(va);
+    }
+}
+
+/// Memory-mapped device registers at physical address `va - offset`.
+///
+/// Like `Linear`, but the pages are never executable.
+#[derive(Debug, Clone)]
+pub struct Device {
+    offset: usize,
+}
+
+impl Device {
+    pub fn new(offset: usize) -> Self {
+        Device { offset }
+    }
+}
+
+impl MemoryHandler for Device {
+    fn box_clone(&self) -> Box<dyn MemoryHandler> {
+        Box::new(self.clone())
+    }
+
+    fn map(&self, pt: &mut PageTableImpl, va: usize, attr: &MemoryAttr) -> Result<(), MapToError> {
+        let entry = pt.map(va, va.wrapping_sub(self.offset))?;
+        attr.apply(entry);
+        entry.set_execute(false);
+        Ok(())
+    }
+
+    fn unmap(&self, pt: &mut PageTableImpl, va: usize) {
+        pt.unmap(va);
+    }
+
+    /// Maps the whole range with a single TLB flush.
+    fn map_area(&self, pt: &mut PageTableImpl, start: usize, end: usize, attr: &MemoryAttr) -> Result<(), MapToError> {
+        let mut device = MemoryAttr::new();
+        if attr.user() {
+            device = device.set_user();
+        }
+        if attr.readonly() {
+            device = device.set_readonly();
+        }
+        pt.map_range(PageRange::new(start, end), &device, |va| va.wrapping_sub(self.offset))
+    }
+
+    fn unmap_area(&self, pt: &mut PageTableImpl, start: usize, end: usize) {
+        pt.unmap_range(PageRange::new(start, end));
+    }
+}
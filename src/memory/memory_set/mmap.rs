@@ -0,0 +1,105 @@
+//! Asking an address space for memory without picking the addresses: `map_anonymous`,
+//! `map_shared`, `munmap` and `mprotect` on `MemorySet`.
+//!
+//! These only ever touch the areas in `[MMAP_BASE, MMAP_END)`, which are user pages.
+
+use super::attr::MemoryAttr;
+use crate::consts::PAGE_SIZE;
+use crate::address::Frame;
+use crate::frame_tracker::FrameTracker;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bitflags::bitflags;
+
+bitflags! {
+    /// Access permissions of a mapping.
+    pub struct Prot: usize {
+        const READ =    1 << 0;
+        const WRITE =   1 << 1;
+        const EXEC =    1 << 2;
+    }
+}
+
+bitflags! {
+    pub struct MapFlags: usize {
+        /// Clones of the address space share the frames instead of copying them.
+        const SHARED =      1 << 0;
+        /// Backs every page right away instead of on first touch.
+        const POPULATE =    1 << 1;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MmapError {
+    /// Zero length, or an address that is not page aligned.
+    InvalidArgument,
+    /// No free range large enough, or no frames left.
+    NoMemory,
+    /// Part of the range is not mapped.
+    NotMapped,
+    /// Part of the range is already mapped.
+    AlreadyMapped,
+    /// The permissions cannot be granted: pages are always readable, and never both
+    /// writable and executable.
+    PermissionDenied,
+}
+
+impl Prot {
+    /// The attributes of user pages with these permissions.
+    pub(super) fn attr(self) -> Result<MemoryAttr, MmapError> {
+        if !self.contains(Prot::READ) || self.contains(Prot::WRITE | Prot::EXEC) {
+            return Err(MmapError::PermissionDenied);
+        }
+        let mut attr = MemoryAttr::new().set_user();
+        if !self.contains(Prot::WRITE) {
+            attr = attr.set_readonly();
+        }
+        if self.contains(Prot::EXEC) {
+            attr = attr.set_execute();
+        }
+        Ok(attr)
+    }
+}
+
+/// Zeroed frames that can be mapped into several address spaces with `map_shared`.
+#[derive(Debug)]
+pub struct SharedMemory {
+    frames: Vec<FrameTracker>,
+}
+
+impl SharedMemory {
+    /// Allocates `len` bytes, rounded up to whole pages.
+    pub fn new(len: usize) -> Option<Arc<Self>> {
+        let frames = (0..len.div_ceil(PAGE_SIZE))
+            .map(|_| FrameTracker::new())
+            .collect::<Option<Vec<_>>>()?;
+        Some(Arc::new(SharedMemory { frames }))
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len() * PAGE_SIZE
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    pub fn frame(&self, i: usize) -> Frame {
+        self.frames[i].frame()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prot_becomes_user_attributes() {
+        let attr = (Prot::READ | Prot::WRITE).attr().unwrap();
+        assert!(attr.user() && !attr.readonly() && !attr.execute());
+        let attr = (Prot::READ | Prot::EXEC).attr().unwrap();
+        assert!(attr.readonly() && attr.execute());
+        assert_eq!(Prot::WRITE.attr(), Err(MmapError::PermissionDenied));
+        assert_eq!(Prot::all().attr(), Err(MmapError::PermissionDenied));
+    }
+}
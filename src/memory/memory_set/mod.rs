@@ -0,0 +1,440 @@
+pub mod area;
+pub mod attr;
+pub mod handler;
+pub mod mmap;
+
+use area::MemoryArea;
+use attr::MemoryAttr;
+use handler::{ByFrame, Delay, Linear, MemoryHandler, Shared};
+use mmap::{MapFlags, MmapError, Prot, SharedMemory};
+use crate::consts::*;
+use crate::page_table::{is_kernel_shared, MapToError};
+use crate::paging::{access_pa_via_va, PageEntry, PageTableImpl};
+use crate::register::satp;
+use crate::register::scause::Exception;
+use crate::utils::mutex::Mutex;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// An address space: a page table and the memory areas mapped into it.
+pub struct MemorySet {
+    areas: Vec<MemoryArea>,
+    page_table: PageTableImpl,
+}
+
+/// The kernel's own address space, installed by `memory::init`.
+pub static KERNEL_MEMORY_SET: Mutex<Option<MemorySet>> = Mutex::new(None);
+
+/// The address space `switch_to` installed instead of the kernel's, if any.
+static CURRENT_MEMORY_SET: Mutex<Option<Arc<Mutex<MemorySet>>>> = Mutex::new(None);
+
+/// Switches to `memory_set`, or back to the kernel address space for `None`. Page faults
+/// and accesses to user memory are resolved against the address space switched to.
+///
+/// # Safety
+///
+/// The code and data the kernel is running on must stay mapped in `memory_set`.
+pub unsafe fn switch_to(memory_set: Option<Arc<Mutex<MemorySet>>>) {
+    match &memory_set {
+        Some(memory_set) => memory_set.lock().activate(),
+        None => KERNEL_MEMORY_SET.lock().as_ref().unwrap().activate(),
+    }
+    *CURRENT_MEMORY_SET.lock() = memory_set;
+}
+
+/// Runs `f` on the active address space. Fails instead of waiting if it is locked, e.g. by
+/// the code a page fault interrupted.
+pub fn with_current<T>(f: impl FnOnce(&mut MemorySet) -> T) -> Result<T, PageFaultError> {
+    let current = CURRENT_MEMORY_SET.try_lock().ok_or(PageFaultError::Locked)?;
+    match current.as_ref() {
+        Some(memory_set) => {
+            let mut memory_set = memory_set.try_lock().ok_or(PageFaultError::Locked)?;
+            run_if_active(&mut memory_set, f)
+        }
+        None => {
+            let mut memory_set = KERNEL_MEMORY_SET.try_lock().ok_or(PageFaultError::Locked)?;
+            let satp = satp::read().bits();
+            let memory_set = memory_set.as_mut().ok_or(PageFaultError::UnknownAddressSpace { satp })?;
+            run_if_active(memory_set, f)
+        }
+    }
+}
+
+fn run_if_active<T>(memory_set: &mut MemorySet, f: impl FnOnce(&mut MemorySet) -> T) -> Result<T, PageFaultError> {
+    let satp = satp::read().bits();
+    if memory_set.token() != satp {
+        return Err(PageFaultError::UnknownAddressSpace { satp });
+    }
+    Ok(f(memory_set))
+}
+
+impl MemorySet {
+    /// An address space with the kernel image, the physical memory window, the kernel stacks
+    /// and the device registers mapped.
+    pub fn new() -> Self {
+        let mut memory_set = Self::new_bare().expect("failed to allocate a page table");
+        memory_set
+            .map_kernel_and_physical_memory()
+            .expect("failed to map the kernel");
+        memory_set
+    }
+
+    /// An address space with nothing mapped, not even the kernel, or `None` if there is no
+    /// frame left for its page table.
+    pub fn new_bare() -> Option<Self> {
+        Some(MemorySet {
+            areas: Vec::new(),
+            page_table: PageTableImpl::new_bare()?,
+        })
+    }
+
+    /// Maps every kernel section with the permissions it needs and the physical memory after
+    /// the kernel image read-write through the linear window, and links the table shared by
+    /// every address space for the kernel stacks and the device registers.
+    fn map_kernel_and_physical_memory(&mut self) -> Result<(), PushError> {
+        extern "C" {
+            fn stext();
+            fn etext();
+            fn srodata();
+            fn erodata();
+            fn sdata();
+            fn edata();
+            fn bootstack();
+            fn bootstacktop();
+            fn sbss();
+            fn ebss();
+        }
+        let addr = |symbol: unsafe extern "C" fn()| symbol as *const () as usize;
+        let text = MemoryAttr::new().set_readonly().set_execute();
+        let rodata = MemoryAttr::new().set_readonly();
+        let sections = [
+            (addr(stext), addr(etext), text),
+            (addr(srodata), addr(erodata), rodata),
+            (addr(sdata), addr(edata), MemoryAttr::new()),
+            (addr(bootstack), addr(bootstacktop), MemoryAttr::new()),
+            (addr(sbss), addr(ebss), MemoryAttr::new()),
+            (addr(ebss), access_pa_via_va(super::memory_end()), MemoryAttr::new()),
+        ];
+        for (start, end, attr) in sections {
+            self.push(start, end, attr, Linear::new(PHYSICAL_MEMORY_OFFSET))?;
+        }
+        // Whichever address space is active, the kernel keeps running on the same stacks and
+        // reaches the same devices, including those mapped after this one was created.
+        self.page_table.link_kernel_shared()?;
+        Ok(())
+    }
+
+    /// Maps `[start, end)` with `attr` through `handler` and records it as a new area.
+    pub fn push(
+        &mut self,
+        start: usize,
+        end: usize,
+        attr: MemoryAttr,
+        handler: impl MemoryHandler,
+    ) -> Result<(), PushError> {
+        if !self.test_free_area(start, end) {
+            return Err(PushError::Overlap { start, end });
+        }
+        let area = MemoryArea::new(start, end, Box::new(handler), attr);
+        area.map(&mut self.page_table)?;
+        self.areas.push(area);
+        Ok(())
+    }
+
+    /// Unmaps the area spanning exactly `[start, end)` and returns it.
+    pub fn remove(&mut self, start: usize, end: usize) -> Option<MemoryArea> {
+        let index = self
+            .areas
+            .iter()
+            .position(|area| area.start() == start && area.end() == end)?;
+        let area = self.areas.remove(index);
+        area.unmap(&mut self.page_table);
+        Some(area)
+    }
+
+    /// The area containing `va`, if any.
+    pub fn find_area(&self, va: usize) -> Option<&MemoryArea> {
+        self.areas.iter().find(|area| area.contains(va))
+    }
+
+    /// The page table entry for `va`, if its page table exists.
+    pub fn get_entry(&mut self, va: usize) -> Option<&mut PageEntry> {
+        self.page_table.get_entry(va)
+    }
+
+    /// A copy of this address space that shares the frames of its areas copy-on-write, where
+    /// their handlers allow it. The areas every address space shares, only recorded in the
+    /// kernel address space, are linked instead of copied.
+    pub fn clone_cow(&mut self) -> Result<MemorySet, MapToError> {
+        let mut memory_set = Self::new_bare().ok_or(MapToError::FrameAllocationFailed)?;
+        memory_set.page_table.link_kernel_shared()?;
+        for area in self.areas.iter().filter(|area| !is_kernel_shared(area.start())) {
+            area.clone_cow(&mut self.page_table, &mut memory_set.page_table)?;
+            memory_set.areas.push(area.clone());
+        }
+        Ok(memory_set)
+    }
+
+    /// Resolves a page fault of kind `cause` at `va`: a store to a copy-on-write page gets a
+    /// private frame, anything else is up to the area containing `va`, e.g. mapping a frame
+    /// on first touch.
+    pub fn handle_page_fault(&mut self, va: usize, cause: Exception) -> Result<(), PageFaultError> {
+        let area = self
+            .areas
+            .iter()
+            .find(|area| area.contains(va))
+            .ok_or(PageFaultError::NoArea)?;
+        let cow = match cause {
+            Exception::StorePageFault => self.page_table.copy_on_write(va),
+            _ => Ok(false),
+        };
+        let resolved = match cow {
+            Ok(true) => true,
+            Ok(false) => area.handle_page_fault(&mut self.page_table, va),
+            // No frame left for the copy.
+            Err(_) => false,
+        };
+        if resolved {
+            Ok(())
+        } else {
+            Err(PageFaultError::Unresolved {
+                cause,
+                start: area.start(),
+                end: area.end(),
+                attr: *area.attr(),
+            })
+        }
+    }
+
+    /// Whether `[start, end)` is clear of every existing area.
+    pub fn test_free_area(&self, start: usize, end: usize) -> bool {
+        self.areas.iter().all(|area| !area.is_overlap_with(start, end))
+    }
+
+    /// The lowest address in the mmap window with `len` free bytes after it.
+    pub fn find_free_area(&self, len: usize) -> Option<usize> {
+        self.find_free_area_in(len, MMAP_BASE, MMAP_END)
+    }
+
+    /// The lowest address in `[base, end)` with `len` free bytes after it.
+    pub fn find_free_area_in(&self, len: usize, base: usize, end: usize) -> Option<usize> {
+        if len > end - base {
+            return None;
+        }
+        let mut taken: Vec<(usize, usize)> = self
+            .areas
+            .iter()
+            .filter(|area| area.is_overlap_with(base, end))
+            .map(|area| (area.start(), area.end()))
+            .collect();
+        taken.sort_unstable();
+        let mut start = base;
+        for (area_start, area_end) in taken {
+            if start + len <= area_start {
+                return Some(start);
+            }
+            start = start.max(area_end.next_multiple_of(PAGE_SIZE));
+        }
+        (start + len <= end).then_some(start)
+    }
+
+    /// Maps `len` bytes of zeroed memory somewhere in the mmap window and returns where.
+    pub fn map_anonymous(&mut self, len: usize, prot: Prot, flags: MapFlags) -> Result<usize, MmapError> {
+        if len == 0 {
+            return Err(MmapError::InvalidArgument);
+        }
+        let attr = prot.attr()?;
+        let len = len.checked_next_multiple_of(PAGE_SIZE).ok_or(MmapError::NoMemory)?;
+        let start = self.find_free_area(len).ok_or(MmapError::NoMemory)?;
+        let end = start + len;
+        let result = if flags.contains(MapFlags::SHARED) {
+            let memory = SharedMemory::new(len).ok_or(MmapError::NoMemory)?;
+            self.push(start, end, attr, Shared::new(memory, start))
+        } else if flags.contains(MapFlags::POPULATE) {
+            self.push(start, end, attr, ByFrame::new())
+        } else {
+            self.push(start, end, attr, Delay::new())
+        };
+        result.map_err(PushError::mmap_error)?;
+        Ok(start)
+    }
+
+    /// Maps all of `memory` somewhere in the mmap window and returns where. Every address
+    /// space it is mapped into sees the same frames.
+    pub fn map_shared(&mut self, memory: &Arc<SharedMemory>, prot: Prot) -> Result<usize, MmapError> {
+        let attr = prot.attr()?;
+        let start = self.find_free_area(memory.len()).ok_or(MmapError::NoMemory)?;
+        self.push(start, start + memory.len(), attr, Shared::new(memory.clone(), start))
+            .map_err(PushError::mmap_error)?;
+        Ok(start)
+    }
+
+    /// Unmaps `[addr, addr + len)` in the mmap window, splitting the areas it cuts through.
+    /// Parts of the range that are not mapped are skipped.
+    pub fn munmap(&mut self, addr: usize, len: usize) -> Result<(), MmapError> {
+        let end = Self::mmap_range(addr, len)?;
+        self.split_area_at(addr);
+        self.split_area_at(end);
+        let mut i = 0;
+        while i < self.areas.len() {
+            if addr <= self.areas[i].start() && self.areas[i].end() <= end {
+                let area = self.areas.swap_remove(i);
+                area.unmap(&mut self.page_table);
+            } else {
+                i += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Changes the permissions of `[addr, addr + len)` in the mmap window, which has to be
+    /// mapped completely.
+    pub fn mprotect(&mut self, addr: usize, len: usize, prot: Prot) -> Result<(), MmapError> {
+        let end = Self::mmap_range(addr, len)?;
+        let attr = prot.attr()?;
+        let mut covered = addr;
+        while covered < end {
+            covered = self.find_area(covered).ok_or(MmapError::NotMapped)?.end();
+        }
+        self.split_area_at(addr);
+        self.split_area_at(end);
+        for area in self.areas.iter_mut() {
+            if addr <= area.start() && area.end() <= end {
+                area.protect(&mut self.page_table, attr);
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that `[addr, addr + len)` is a page aligned part of the mmap window and returns
+    /// its end, rounded up to a page.
+    fn mmap_range(addr: usize, len: usize) -> Result<usize, MmapError> {
+        let end = len
+            .checked_next_multiple_of(PAGE_SIZE)
+            .and_then(|len| addr.checked_add(len));
+        match end {
+            Some(end) if len != 0 && addr.is_multiple_of(PAGE_SIZE) && MMAP_BASE <= addr && end <= MMAP_END => Ok(end),
+            _ => Err(MmapError::InvalidArgument),
+        }
+    }
+
+    /// Splits the area `va` lies strictly inside of, if any, so that an area starts at `va`.
+    fn split_area_at(&mut self, va: usize) {
+        if let Some(i) = self.areas.iter().position(|area| area.start() < va && va < area.end()) {
+            let (lower, upper) = self.areas.swap_remove(i).split(va);
+            self.areas.push(lower);
+            self.areas.push(upper);
+        }
+    }
+
+    pub fn areas(&self) -> &[MemoryArea] {
+        &self.areas
+    }
+
+    /// Prints the areas of this address space and what its page table maps.
+    pub fn dump(&self) {
+        for area in self.areas.iter() {
+            println!("{:x?}", area);
+        }
+        self.page_table.dump();
+    }
+
+    pub fn token(&self) -> usize {
+        self.page_table.token()
+    }
+
+    /// Switches to this address space.
+    ///
+    /// # Safety
+    ///
+    /// The code and data the kernel is running on must stay mapped in this address space.
+    pub unsafe fn activate(&self) {
+        self.page_table.activate();
+    }
+}
+
+/// Why `MemorySet::handle_page_fault` could not resolve a fault.
+#[derive(Debug)]
+pub enum PageFaultError {
+    /// The address is outside every memory area.
+    NoArea,
+    /// The area containing the address does not allow the access, or ran out of memory.
+    Unresolved {
+        cause: Exception,
+        start: usize,
+        end: usize,
+        attr: MemoryAttr,
+    },
+    /// The active address space is locked.
+    Locked,
+    /// `satp` points at a page table that was not installed with `switch_to`.
+    UnknownAddressSpace { satp: usize },
+}
+
+impl fmt::Display for PageFaultError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PageFaultError::NoArea => write!(f, "no memory area contains the address"),
+            PageFaultError::Unresolved { cause, start, end, attr } => write!(
+                f,
+                "{:?} not resolved by memory area [{:#x}, {:#x}) {:?}",
+                cause, start, end, attr
+            ),
+            PageFaultError::Locked => write!(f, "address space locked"),
+            PageFaultError::UnknownAddressSpace { satp } => {
+                write!(f, "satp {:#x} is not an address space switched to", satp)
+            }
+        }
+    }
+}
+
+/// Why `MemorySet::push` could not add an area.
+#[derive(Debug)]
+pub enum PushError {
+    /// The range overlaps an existing area.
+    Overlap { start: usize, end: usize },
+    /// Mapping the area failed, e.g. for lack of frames.
+    Map(MapToError),
+}
+
+impl PushError {
+    fn mmap_error(self) -> MmapError {
+        match self {
+            PushError::Overlap { .. } => MmapError::AlreadyMapped,
+            PushError::Map(_) => MmapError::NoMemory,
+        }
+    }
+}
+
+impl From<MapToError> for PushError {
+    fn from(err: MapToError) -> Self {
+        PushError::Map(err)
+    }
+}
+
+impl fmt::Display for PushError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PushError::Overlap { start, end } => {
+                write!(f, "memory area [{:#x}, {:#x}) overlaps an existing one", start, end)
+            }
+            PushError::Map(err) => write!(f, "mapping failed: {:?}", err),
+        }
+    }
+}
+
+impl Default for MemorySet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for MemorySet {
+    fn drop(&mut self) {
+        for area in self.areas.iter() {
+            area.unmap(&mut self.page_table);
+        }
+    }
+}
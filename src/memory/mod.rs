@@ -37,48 +37,227 @@ pub trait AddressL3: Address {
 
 #[macro_use]
 pub mod linked_list;
-mod frame_allocator;
+pub mod frame_allocator;
 mod buddy_system_allocator;
+#[cfg(feature = "debug-heap")]
+mod debug_heap;
 pub mod address;
 pub mod page_table;
 pub mod paging;
+pub mod frame_tracker;
+pub mod layout;
+pub mod stats;
+pub mod slab;
+pub mod memory_set;
+pub mod asid;
+pub mod swap;
+pub mod ioremap;
+pub mod kernel_stack;
 
 
-use buddy_system_allocator::LockedHeap;
-use frame_allocator::SEGMENT_TREE_ALLOCATOR as FRAME_ALLOCATOR;
+use buddy_system_allocator::Heap;
+use slab::{CacheId, SlabHeap};
+use frame_allocator::{FrameAllocatorImpl, FrameRefCounts, PhysFrameAllocator, FRAME_ALLOCATOR, FRAME_REF_COUNTS};
 use address::Frame;
+use layout::MemoryLayout;
+use memory_set::{MemorySet, PageFaultError, KERNEL_MEMORY_SET};
+use stats::MemoryStats;
 use crate::consts::*;
+use crate::register::scause::Exception;
+use paging::access_pa_via_va;
+use core::alloc::Layout;
+use core::cmp::{max, min};
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
+const HEAP_ORDER: usize = 32;
 
+static TOTAL_FRAMES: AtomicUsize = AtomicUsize::new(0);
+static USABLE_FRAMES: AtomicUsize = AtomicUsize::new(0);
+static MEMORY_END: AtomicUsize = AtomicUsize::new(0);
 
 
-pub fn init(l: usize, r: usize) {
-    FRAME_ALLOCATOR.lock().init(l, r);
+
+
+pub fn init(layout: &MemoryLayout) {
+    init_frame_allocator(layout);
     init_heap();
+    paging::init_asids();
+    remap_kernel();
     println!("++++ setup memory!    ++++");
 }
 
+/// End of the physical memory the frame allocator manages.
+pub fn memory_end() -> usize {
+    MEMORY_END.load(Ordering::Relaxed)
+}
+
+/// Resolves a page fault of kind `cause` at `va` in the active address space.
+pub fn handle_page_fault(va: usize, cause: Exception) -> Result<(), PageFaultError> {
+    memory_set::with_current(|memory_set| memory_set.handle_page_fault(va, cause))?
+}
+
+/// Allocates a frame, swapping pages out to make room once physical memory is depleted.
 pub fn alloc_frame() -> Option<Frame> {
-    Some(Frame::of_ppn(FRAME_ALLOCATOR.lock().alloc()))
+    loop {
+        let frame = FRAME_ALLOCATOR.lock().alloc();
+        if frame.is_some() || !swap::swap_out() {
+            return frame.map(Frame::of_ppn);
+        }
+    }
 }
 
 pub fn dealloc_frame(f: Frame) {
     FRAME_ALLOCATOR.lock().dealloc(f.number())
 }
 
+/// Adds a reference to the allocated frame `f`, e.g. when a copy-on-write mapping of it
+/// is made.
+pub fn share_frame(f: Frame) {
+    FRAME_REF_COUNTS.lock().share(f.number())
+}
+
+/// Number of references to the allocated frame `f`.
+pub fn frame_refs(f: Frame) -> usize {
+    FRAME_REF_COUNTS.lock().refs(f.number())
+}
+
+/// Drops a reference to `f` and frees it once the last one is gone.
+pub fn release_frame(f: Frame) {
+    if FRAME_REF_COUNTS.lock().unshare(f.number()) {
+        dealloc_frame(f);
+    }
+}
+
+/// Allocates `count` physically contiguous frames, the first one aligned to `1 << align_log2` frames.
+pub fn alloc_frames(count: usize, align_log2: usize) -> Option<Frame> {
+    FRAME_ALLOCATOR
+        .lock()
+        .alloc_contiguous(count, align_log2)
+        .map(Frame::of_ppn)
+}
+
+pub fn dealloc_frames(f: Frame, count: usize) {
+    FRAME_ALLOCATOR.lock().dealloc_contiguous(f.number(), count)
+}
+
+/// Takes a snapshot of frame allocator and kernel heap usage.
+pub fn stats() -> MemoryStats<HEAP_ORDER> {
+    let free_frames = FRAME_ALLOCATOR.lock().free_frames();
+    let slab_caches = DYNAMIC_ALLOCATOR.stats();
+    let heap = DYNAMIC_ALLOCATOR.heap().lock();
+    MemoryStats {
+        total_frames: TOTAL_FRAMES.load(Ordering::Relaxed),
+        usable_frames: USABLE_FRAMES.load(Ordering::Relaxed),
+        free_frames,
+        heap_total_bytes: heap.stats_total_bytes(),
+        heap_user_bytes: heap.stats_alloc_user(),
+        heap_allocated_bytes: heap.stats_alloc_actual(),
+        heap_free_blocks: core::array::from_fn(|class| heap.stats_free_blocks(class)),
+        slab_caches,
+    }
+}
+
+/// Creates a named slab cache for objects of `layout`.
+pub fn create_cache(name: &'static str, layout: Layout) -> Option<CacheId> {
+    DYNAMIC_ALLOCATOR.create_cache(name, layout)
+}
+
+pub fn cache_alloc(id: CacheId) -> Option<NonNull<u8>> {
+    DYNAMIC_ALLOCATOR.cache_alloc(id)
+}
+
+/// # Safety
+///
+/// `ptr` must come from `cache_alloc` with the same `id`.
+pub unsafe fn cache_dealloc(id: CacheId, ptr: NonNull<u8>) {
+    DYNAMIC_ALLOCATOR.cache_dealloc(id, ptr)
+}
+
+/// Hands every page of RAM in `layout` to the frame allocator, except for the holes between
+/// memory regions and the reserved regions. The bookkeeping of the allocator and of the frame
+/// reference counts is taken from the first usable region large enough to hold it.
+fn init_frame_allocator(layout: &MemoryLayout) {
+    let mut layout = layout.clone();
+    let memory = layout.memory();
+    assert!(!memory.is_empty(), "no physical memory found!");
+    let l = memory[0].start.div_ceil(PAGE_SIZE);
+    let mut r = memory.iter().map(|region| region.end).max().unwrap() / PAGE_SIZE;
+    if r > PHYSICAL_MEMORY_LIMIT / PAGE_SIZE {
+        r = PHYSICAL_MEMORY_LIMIT / PAGE_SIZE;
+        println!("ignoring physical memory above {:#x}", PHYSICAL_MEMORY_LIMIT);
+    }
+
+    let allocator_size = FrameAllocatorImpl::metadata_size(l, r).next_multiple_of(PAGE_SIZE);
+    let size = allocator_size + FrameRefCounts::metadata_size(l, r).next_multiple_of(PAGE_SIZE);
+    let mut metadata = None;
+    layout.for_each_usable(|region| {
+        let start = region.start.next_multiple_of(PAGE_SIZE);
+        if metadata.is_none() && start + size <= region.end && start + size <= r * PAGE_SIZE {
+            metadata = Some(start);
+        }
+    });
+    let metadata = metadata.expect("no room for the frame allocator!");
+    layout.add_reserved(metadata, metadata + size);
+
+    let (allocator_metadata, ref_counts_metadata) = unsafe {
+        core::slice::from_raw_parts_mut(access_pa_via_va(metadata) as *mut u8, size)
+    }
+    .split_at_mut(allocator_size);
+    FRAME_REF_COUNTS.lock().init(l, r, ref_counts_metadata);
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    allocator.init(l, r, allocator_metadata);
+    layout.for_each_usable(|region| {
+        let start = region.start.div_ceil(PAGE_SIZE);
+        let end = min(region.end / PAGE_SIZE, r);
+        if start < end {
+            allocator.dealloc_contiguous(start, end - start);
+        }
+    });
+    TOTAL_FRAMES.store(r - l, Ordering::Relaxed);
+    MEMORY_END.store(r * PAGE_SIZE, Ordering::Relaxed);
+    USABLE_FRAMES.store(allocator.free_frames(), Ordering::Relaxed);
+    println!("frame allocator: {}, metadata at {:#x}", allocator.name(), metadata);
+}
+
 fn init_heap() {
     static mut HEAP: [u8; KERNEL_HEAP_SIZE] = [0; KERNEL_HEAP_SIZE];
     unsafe {
         DYNAMIC_ALLOCATOR
+            .heap()
             .lock()
             .init(HEAP.as_ptr() as usize, KERNEL_HEAP_SIZE);
     }
 }
 
-#[global_allocator]
-static DYNAMIC_ALLOCATOR: LockedHeap<32> = LockedHeap::empty();
+/// Replaces the boot page table, which maps the whole gigabyte around the kernel as
+/// VRWXAD, with one that gives each kernel section only the permissions it needs.
+fn remap_kernel() {
+    let memory_set = MemorySet::new();
+    unsafe { memory_set.activate() };
+    *KERNEL_MEMORY_SET.lock() = Some(memory_set);
+    println!("remap kernel end!");
+}
+
+/// Called with the heap locked when an allocation does not fit: maps a naturally aligned
+/// run of fresh frames through the linear window and adds it to the heap.
+fn grow_heap(heap: &mut Heap<HEAP_ORDER>, layout: &Layout) {
+    let needed = max(layout.size().next_power_of_two(), max(layout.align(), PAGE_SIZE));
+    for size in [max(needed, KERNEL_HEAP_GROW_SIZE), needed] {
+        let count = size / PAGE_SIZE;
+        if let Some(frame) = alloc_frames(count, count.trailing_zeros() as usize) {
+            let start = access_pa_via_va(frame.number() * PAGE_SIZE);
+            unsafe { heap.add_to_heap(start, start + size) };
+            return;
+        }
+    }
+}
+
+#[cfg_attr(not(test), global_allocator)]
+static DYNAMIC_ALLOCATOR: SlabHeap<HEAP_ORDER> = SlabHeap::new(grow_heap);
 
+#[cfg(not(test))]
 #[alloc_error_handler]
-fn alloc_error_handler(_: core::alloc::Layout) -> ! {
-    panic!("alloc_error_handler do nothing but panic!");
+fn alloc_error_handler(layout: Layout) -> ! {
+    panic!("out of memory: cannot allocate {:?}", layout);
 }
\ No newline at end of file
@@ -1,12 +1,45 @@
 use crate::address::*;
+use crate::consts::{KERNEL_SHARED_BASE, KERNEL_SHARED_END};
 use bitflags::bitflags;
 
 use core::convert::TryInto;
-use core::fmt::{Debug, Error, Formatter};
+use core::fmt::{Debug, Display, Error, Formatter};
 use core::marker::PhantomData;
 use core::ops::{Index, IndexMut};
-unsafe fn sfence_vma(asid: usize, va: usize) {
-    core::arch::asm!("sfence.vma {0}, {1}", in(reg) asid, in(reg) va);
+
+/// Whether `va` is in `[KERNEL_SHARED_BASE, KERNEL_SHARED_END)`, which every address space
+/// maps through the same table.
+pub(crate) fn is_kernel_shared(va: usize) -> bool {
+    (KERNEL_SHARED_BASE..KERNEL_SHARED_END).contains(&va)
+}
+
+/// Flushes `va` in the address space tagged with `asid`, or in every address space if `va`
+/// is shared by all of them.
+#[inline(always)]
+pub(crate) unsafe fn sfence_vma(asid: usize, va: usize) {
+    if is_kernel_shared(va) {
+        sfence_vma_all_asids(va);
+    } else {
+        core::arch::asm!("sfence.vma {0}, {1}", in(reg) va, in(reg) asid);
+    }
+}
+
+/// Flushes `va` in every address space.
+#[inline(always)]
+pub(crate) unsafe fn sfence_vma_all_asids(va: usize) {
+    core::arch::asm!("sfence.vma {0}, zero", in(reg) va);
+}
+
+/// Flushes every page of the address space tagged with `asid`.
+#[inline(always)]
+pub(crate) unsafe fn sfence_vma_asid(asid: usize) {
+    core::arch::asm!("sfence.vma zero, {0}", in(reg) asid);
+}
+
+/// Flushes the whole TLB.
+#[inline(always)]
+pub(crate) unsafe fn sfence_vma_all() {
+    core::arch::asm!("sfence.vma zero, zero");
 }
 pub type Entries64 = [PageTableEntryX64; RV64_ENTRY_COUNT];
 
@@ -46,6 +79,11 @@ impl<T: PTEIterableSlice<E>, E: PTE> PageTableWith<T, E> {
             entry.set_unused();
         }
     }
+
+    /// Whether every entry is unused.
+    pub fn is_empty(&self) -> bool {
+        self.entries.to_pte_slice().iter().all(|entry| entry.is_unused())
+    }
 }
 
 impl<T: PTEIterableSlice<E>, E: PTE> Index<usize> for PageTableWith<T, E> {
@@ -85,7 +123,15 @@ pub trait PTE {
     fn addr<T: PhysicalAddress + Clone + AddressX64>(&self) -> T;
     fn frame<T: PhysicalAddress + Clone + AddressX64>(&self) -> FrameWith<T>;
     fn set<T: PhysicalAddress + Clone + AddressX64>(&mut self, frame: FrameWith<T>, flags: PageTableFlags);
+    /// Replaces the page number, leaving every flag as it is; `set` forces A and D on.
+    fn set_ppn(&mut self, ppn: usize);
     fn flags_mut(&mut self) -> &mut PageTableFlags;
+
+    /// A valid entry with any of R/W/X set maps memory instead of pointing to the next table.
+    fn is_leaf(&self) -> bool {
+        self.flags().contains(PageTableFlags::VALID)
+            && self.flags().intersects(PageTableFlags::READABLE | PageTableFlags::WRITABLE | PageTableFlags::EXECUTABLE)
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -118,6 +164,9 @@ impl PTE for PageTableEntryX64 {
         flags |= EF::ACCESSED | EF::DIRTY;
         self.0 = ((frame.number() << 10) | flags.bits()) as u64;
     }
+    fn set_ppn(&mut self, ppn: usize) {
+        self.0 = ((ppn << 10) | self.flags().bits()) as u64;
+    }
     fn flags_mut(&mut self) -> &mut PageTableFlags {
         unsafe { &mut *(self as *mut _ as *mut PageTableFlags) }
     }
@@ -182,6 +231,91 @@ bitflags! {
 
 type EF = PageTableFlags;
 
+/// Marks a non-leaf entry pointing at a table shared with other page tables, which this one
+/// must not free.
+const SHARED_TABLE: PageTableFlags = PageTableFlags::RESERVED1;
+
+/// Size of the memory mapped by one leaf entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageSize {
+    Size4KiB,
+    /// Megapage, a leaf one level above the last.
+    Size2MiB,
+    /// Gigapage, a leaf in the Sv39 root table or one level below the Sv48 root.
+    Size1GiB,
+    /// Terapage, a leaf in the Sv48 root table.
+    Size512GiB,
+}
+
+impl PageSize {
+    /// Every size from the smallest up: the `n`-th is mapped by leaves `n` levels above the
+    /// last level.
+    pub const ALL: [PageSize; 4] = [PageSize::Size4KiB, PageSize::Size2MiB, PageSize::Size1GiB, PageSize::Size512GiB];
+
+    pub const fn bytes(self) -> usize {
+        match self {
+            PageSize::Size4KiB => 1 << 12,
+            PageSize::Size2MiB => 1 << 21,
+            PageSize::Size1GiB => 1 << 30,
+            PageSize::Size512GiB => 1 << 39,
+        }
+    }
+
+    /// Number of 4 KiB pages covered.
+    pub const fn pages(self) -> usize {
+        self.bytes() >> 12
+    }
+}
+
+/// A run of virtually and physically contiguous pages of one size mapped with the same flags,
+/// as yielded by `mappings`. Runs are described by their length since the last one may end
+/// at the very top of the address space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappingRun {
+    pub va: usize,
+    pub pa: usize,
+    /// Length in bytes.
+    pub len: usize,
+    pub flags: PageTableFlags,
+    pub size: PageSize,
+}
+
+impl MappingRun {
+    /// Whether `next` continues this run.
+    fn is_continued_by(&self, next: &MappingRun) -> bool {
+        next.va == self.va.wrapping_add(self.len)
+            && next.pa == self.pa + self.len
+            && next.flags == self.flags
+            && next.size == self.size
+    }
+}
+
+impl Display for MappingRun {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        write!(
+            f,
+            "{:#018x}-{:#018x} -> {:#x}-{:#x} ",
+            self.va,
+            self.va + (self.len - 1),
+            self.pa,
+            self.pa + (self.len - 1)
+        )?;
+        for (flag, c) in [
+            (EF::VALID, 'V'),
+            (EF::READABLE, 'R'),
+            (EF::WRITABLE, 'W'),
+            (EF::EXECUTABLE, 'X'),
+            (EF::USER, 'U'),
+            (EF::GLOBAL, 'G'),
+            (EF::ACCESSED, 'A'),
+            (EF::DIRTY, 'D'),
+        ] {
+            write!(f, "{}", if self.flags.contains(flag) { c } else { '-' })?;
+        }
+        write!(f, " {:?} x {}", self.size, self.len / self.size.bytes())
+    }
+}
+
 // A trait for types that can allocate a frame of memory.
 pub trait FrameAllocatorFor<P: PhysicalAddress + Clone + AddressX64> {
     /// Allocate a frame of the appropriate size and return it if possible.
@@ -236,9 +370,23 @@ pub trait Mapper {
         allocator: &mut impl FrameAllocatorFor<<Self as Mapper>::P>,
     ) -> Result<Self::MapperFlush, MapToError>;
 
+    /// Maps the `size` page starting at `page` to the `size` frame starting at `frame` with a
+    /// single leaf entry in the table level for `size`. Both must be aligned to `size`, and
+    /// `flags` must contain at least one of R/W/X.
+    fn map_to_huge(
+        &mut self,
+        page: PageWith<Self::V>,
+        frame: FrameWith<Self::P>,
+        size: PageSize,
+        flags: PageTableFlags,
+        allocator: &mut impl FrameAllocatorFor<<Self as Mapper>::P>,
+    ) -> Result<Self::MapperFlush, MapToError>;
+
     /// Removes a mapping from the page table and returns the frame that used to be mapped.
+    /// A huge page is unmapped as a whole through its first page.
     ///
-    /// Note that no page tables or pages are deallocated.
+    /// Note that no page tables or pages are deallocated; see `free_empty_tables` on the
+    /// page table types for reclaiming tables.
     fn unmap(
         &mut self,
         page: PageWith<Self::V>,
@@ -259,7 +407,8 @@ pub trait Mapper {
         })
     }
 
-    /// Return the frame that the specified page is mapped to.
+    /// Return the frame that the specified page is mapped to, which lies inside a huge frame
+    /// when the page is part of a huge page.
     fn translate_page(&mut self, page: PageWith<Self::V>) -> Option<FrameWith<Self::P>> {
         match self.ref_entry(&page) {
             Ok(e) => {
@@ -291,7 +440,7 @@ pub trait MapperFlushable {
     fn new<T: VirtualAddress + Clone + AddressX64>(page: PageWith<T>) -> Self;
     /// Flush the page from the TLB to ensure that the newest mapping is used.
     fn flush(self);
-    /// Don't flush the TLB and silence the “must be used? warning.
+    /// Don't flush the TLB and silence the "must be used" warning.
     fn ignore(self);
 }
 
@@ -303,14 +452,83 @@ impl MapperFlushable for MapperFlush {
     fn new<T: VirtualAddress + Clone + AddressX64>(page: PageWith<T>) -> Self {
         MapperFlush(page.start_address().as_usize())
     }
+    /// Flushes the page in every address space.
     fn flush(self) {
         unsafe {
-            sfence_vma(0, self.0);
+            sfence_vma_all_asids(self.0);
         }
     }
     fn ignore(self) {}
 }
 
+impl MapperFlush {
+    /// Flushes the page only in the address space tagged with `asid`.
+    pub fn flush_asid(self, asid: usize) {
+        unsafe {
+            sfence_vma(asid, self.0);
+        }
+    }
+}
+
+/// Collects the flushes of many page table changes to issue them together. Past `N` pages,
+/// flushing the whole address space is cheaper than flushing every page.
+#[must_use = "Page Table changes must be flushed or ignored."]
+pub struct FlushBatch<const N: usize> {
+    pages: [usize; N],
+    len: usize,
+    overflowed: bool,
+    /// Some page is shared by every address space.
+    shared: bool,
+}
+
+impl<const N: usize> FlushBatch<N> {
+    pub fn new() -> Self {
+        FlushBatch {
+            pages: [0; N],
+            len: 0,
+            overflowed: false,
+            shared: false,
+        }
+    }
+
+    pub fn push(&mut self, flush: MapperFlush) {
+        self.shared |= is_kernel_shared(flush.0);
+        if self.len < N {
+            self.pages[self.len] = flush.0;
+            self.len += 1;
+        } else {
+            self.overflowed = true;
+        }
+    }
+
+    /// Drops the flushes, e.g. because the caller flushes everything anyway.
+    pub fn ignore(self) {}
+
+    /// The pages to flush one by one, or `None` if the whole address space is flushed.
+    pub fn pages(&self) -> Option<&[usize]> {
+        (!self.overflowed).then_some(&self.pages[..self.len])
+    }
+
+    /// Flushes the pages in the address space tagged with `asid`, and in every other one for
+    /// the pages they all share.
+    pub fn flush_asid(self, asid: usize) {
+        match self.pages() {
+            Some(pages) => {
+                for &va in pages {
+                    unsafe { sfence_vma(asid, va) };
+                }
+            }
+            None if self.shared => unsafe { sfence_vma_all() },
+            None => unsafe { sfence_vma_asid(asid) },
+        }
+    }
+}
+
+impl<const N: usize> Default for FlushBatch<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 /// This error is returned from `map_to` and similar methods.
 #[derive(Debug)]
@@ -355,59 +573,236 @@ impl<T: Mapper> MapperExt for T {
 }
 
 
-/// This struct is a three-level page table with `Mapper` trait implemented.
-pub struct Rv39PageTableWith<'a, V: VirtualAddress + AddressL3, FL: MapperFlushable> {
+/// This struct is a page table of `LEVELS` levels with `Mapper` trait implemented: three
+/// for Sv39, four for Sv48. Use it through `Rv39PageTableWith` or `Rv48PageTableWith`.
+pub struct RvPageTableWith<'a, V: VirtualAddress, FL: MapperFlushable, const LEVELS: usize> {
     root_table: &'a mut PageTableX64,
     linear_offset: u64, // VA = PA + linear_offset
     phantom: PhantomData<(V, FL)>,
 }
-impl<'a, V, FL> Rv39PageTableWith<'a, V, FL>
+
+/// A three-level Sv39 page table.
+pub type Rv39PageTableWith<'a, V, FL> = RvPageTableWith<'a, V, FL, 3>;
+/// A four-level Sv48 page table.
+pub type Rv48PageTableWith<'a, V, FL> = RvPageTableWith<'a, V, FL, 4>;
+
+impl<'a, V, FL, const LEVELS: usize> RvPageTableWith<'a, V, FL, LEVELS>
 where
-    V: VirtualAddress + AddressL3 + Clone + AddressX64,
+    V: VirtualAddress + Clone + AddressX64,
     FL: MapperFlushable,
 {
     pub fn new(table: &'a mut PageTableX64, linear_offset: usize) -> Self {
-        Rv39PageTableWith {
+        RvPageTableWith {
             root_table: table,
             linear_offset: linear_offset as u64,
             phantom: PhantomData,
         }
     }
 
-    fn create_p1_if_not_exist(
+    /// Page size of a leaf entry at `level`, 0 being the root.
+    fn level_size(level: usize) -> PageSize {
+        PageSize::ALL[LEVELS - 1 - level]
+    }
+
+    /// The level at which a leaf entry maps a `size` page.
+    fn level_of(size: PageSize) -> usize {
+        let height = PageSize::ALL.iter().position(|&s| s == size).unwrap();
+        assert!(height < LEVELS, "{:?} pages need more than {} levels", size, LEVELS);
+        LEVELS - 1 - height
+    }
+
+    /// Indices of `page` in the table at each level, from the root down.
+    fn indices(page: &PageWith<V>) -> [usize; LEVELS] {
+        core::array::from_fn(|level| (page.number() >> (9 * (LEVELS - 1 - level))) % ENTRY_COUNT)
+    }
+
+    /// The canonical virtual address of the page with these `indices`, zero filling the
+    /// missing lower levels.
+    fn address_of(indices: &[usize]) -> usize {
+        let vpn = (0..LEVELS).fold(0, |vpn, level| (vpn << 9) | indices.get(level).copied().unwrap_or(0));
+        let bits = 12 + 9 * LEVELS;
+        let va = vpn << 12;
+        if va & (1 << (bits - 1)) != 0 {
+            va | !((1 << bits) - 1)
+        } else {
+            va
+        }
+    }
+
+    /// Follows `page` down from the root and returns the entry the walk ends at: a leaf, an
+    /// unused entry, or the level-1 entry, along with the size of page it maps.
+    fn walk(&mut self, page: &PageWith<V>) -> (&mut PageTableEntryX64, PageSize) {
+        let indices = Self::indices(page);
+        let mut table: &mut PageTableX64 = self.root_table;
+        for level in 0..LEVELS - 1 {
+            let entry = &table[indices[level]];
+            if entry.is_unused() || entry.is_leaf() {
+                return (&mut table[indices[level]], Self::level_size(level));
+            }
+            let frame = entry.frame::<PhysAddrSv39>();
+            table = unsafe { frame.as_kernel_mut(self.linear_offset) };
+        }
+        (&mut table[indices[LEVELS - 1]], PageSize::Size4KiB)
+    }
+
+    /// Returns the table at `level` (0 being the root) on the way to `page`, allocating the
+    /// missing tables above it.
+    fn create_table(
         &mut self,
-        p3_index: usize,
-        p2_index: usize,
+        page: &PageWith<V>,
+        level: usize,
         allocator: &mut impl FrameAllocatorFor<<Self as Mapper>::P>,
     ) -> Result<&mut PageTableX64, MapToError> {
-        let p2_table = if self.root_table[p3_index].is_unused() {
-            let frame = allocator.alloc().ok_or(MapToError::FrameAllocationFailed)?;
-            self.root_table[p3_index].set(frame.clone(), PageTableFlags::VALID);
-            let p2_table: &mut PageTableX64 = unsafe { frame.as_kernel_mut(self.linear_offset) };
-            p2_table.zero();
-            p2_table
-        } else {
-            let frame = self.root_table[p3_index].frame::<PhysAddrSv39>();
-            unsafe { frame.as_kernel_mut(self.linear_offset) }
-        };
-        if p2_table[p2_index].is_unused() {
-            let frame = allocator.alloc().ok_or(MapToError::FrameAllocationFailed)?;
-            p2_table[p2_index].set(frame.clone(), PageTableFlags::VALID);
-            let p1_table: &mut PageTableX64 = unsafe { frame.as_kernel_mut(self.linear_offset) };
-            p1_table.zero();
-            Ok(p1_table)
-        } else {
-            let frame = p2_table[p2_index].frame::<PhysAddrSv39>();
-            let p1_table: &mut PageTableX64 = unsafe { frame.as_kernel_mut(self.linear_offset) };
-            Ok(p1_table)
+        let indices = Self::indices(page);
+        let mut table: &mut PageTableX64 = self.root_table;
+        for &index in &indices[..level] {
+            let entry = &mut table[index];
+            if entry.is_unused() {
+                let frame = allocator.alloc().ok_or(MapToError::FrameAllocationFailed)?;
+                entry.set(frame, PageTableFlags::VALID);
+                let next: &mut PageTableX64 = unsafe { frame.as_kernel_mut(self.linear_offset) };
+                next.zero();
+                table = next;
+            } else if entry.is_leaf() {
+                return Err(MapToError::ParentEntryHugePage);
+            } else {
+                let frame = entry.frame::<PhysAddrSv39>();
+                table = unsafe { frame.as_kernel_mut(self.linear_offset) };
+            }
+        }
+        Ok(table)
+    }
+
+    /// Points the entry for the 1 GiB region containing `page` at `table`, a table of 2 MiB
+    /// entries shared with other page tables, allocating the tables above it. Whatever is
+    /// mapped in the region through one of them is mapped in all of them.
+    pub fn link_shared_table(
+        &mut self,
+        page: &PageWith<V>,
+        table: FrameWith<<Self as Mapper>::P>,
+        allocator: &mut impl FrameAllocatorFor<<Self as Mapper>::P>,
+    ) -> Result<(), MapToError> {
+        let level = Self::level_of(PageSize::Size1GiB);
+        let parent = self.create_table(page, level, allocator)?;
+        let entry = &mut parent[Self::indices(page)[level]];
+        if !entry.is_unused() {
+            return Err(MapToError::PageAlreadyMapped);
+        }
+        entry.set(table, PageTableFlags::VALID | SHARED_TABLE);
+        Ok(())
+    }
+
+    /// Frees the tables on the way to `page` that are left without any valid entry, bottom
+    /// up, and returns how many were freed. The root table is never freed. Since the entries
+    /// pointing to them are gone, stale translations may remain in the TLB until a full flush. Shared tables are
+    /// never freed.
+    pub fn free_empty_tables(
+        &mut self,
+        page: &PageWith<V>,
+        deallocator: &mut impl FrameDeallocatorFor<<Self as Mapper>::P>,
+    ) -> usize {
+        let indices = Self::indices(page);
+        let mut path: [*mut PageTableX64; LEVELS] = [self.root_table; LEVELS];
+        let mut depth = 1;
+        while depth < LEVELS {
+            let entry = unsafe { &(&*path[depth - 1])[indices[depth - 1]] };
+            if entry.is_unused() || entry.is_leaf() {
+                break;
+            }
+            path[depth] = unsafe { entry.frame::<PhysAddrSv39>().as_kernel_mut(self.linear_offset) };
+            depth += 1;
+        }
+        let mut freed = 0;
+        for level in (1..depth).rev() {
+            let entry = unsafe { &mut (&mut *path[level - 1])[indices[level - 1]] };
+            if unsafe { !(*path[level]).is_empty() } || entry.flags().contains(SHARED_TABLE) {
+                break;
+            }
+            deallocator.dealloc(entry.frame());
+            entry.set_unused();
+            freed += 1;
+        }
+        freed
+    }
+
+    /// Frees every table below the root and clears the root. Frames mapped by leaf entries
+    /// belong to whoever mapped them and are left alone, and so do shared tables.
+    pub fn free_tables(&mut self, deallocator: &mut impl FrameDeallocatorFor<<Self as Mapper>::P>) {
+        unsafe { Self::free_subtables(self.root_table, 0, self.linear_offset, deallocator) };
+        self.root_table.zero();
+    }
+
+    /// Every leaf entry as a run of one page, in address order.
+    fn leaves(&self) -> impl Iterator<Item = MappingRun> + '_ {
+        let mut tables: [*const PageTableX64; LEVELS] = [&*self.root_table; LEVELS];
+        let mut indices = [0usize; LEVELS];
+        let mut level = 0;
+        let linear_offset = self.linear_offset;
+        core::iter::from_fn(move || loop {
+            if indices[level] == ENTRY_COUNT {
+                if level == 0 {
+                    return None;
+                }
+                level -= 1;
+                indices[level] += 1;
+                continue;
+            }
+            let entry = unsafe { &(&*tables[level])[indices[level]] };
+            if entry.is_unused() {
+                indices[level] += 1;
+            } else if entry.is_leaf() || level == LEVELS - 1 {
+                let size = Self::level_size(level);
+                let va = Self::address_of(&indices[..=level]);
+                indices[level] += 1;
+                return Some(MappingRun {
+                    va,
+                    pa: entry.addr::<PhysAddrSv39>().as_usize(),
+                    len: size.bytes(),
+                    flags: entry.flags(),
+                    size,
+                });
+            } else {
+                let frame = entry.frame::<PhysAddrSv39>();
+                tables[level + 1] = unsafe { frame.as_kernel_mut::<PageTableX64>(linear_offset) };
+                level += 1;
+                indices[level] = 0;
+            }
+        })
+    }
+
+    /// What is mapped where: the leaf entries in address order, with adjacent pages of the
+    /// same size and flags that map contiguous frames merged into one run.
+    pub fn mappings(&self) -> impl Iterator<Item = MappingRun> + '_ {
+        let mut leaves = self.leaves().peekable();
+        core::iter::from_fn(move || {
+            let mut run = leaves.next()?;
+            while let Some(next) = leaves.next_if(|next| run.is_continued_by(next)) {
+                run.len += next.len;
+            }
+            Some(run)
+        })
+    }
+
+    unsafe fn free_subtables(
+        table: &mut PageTableX64,
+        level: usize,
+        linear_offset: u64,
+        deallocator: &mut impl FrameDeallocatorFor<<Self as Mapper>::P>,
+    ) {
+        for entry in table.entries.to_pte_slice() {
+            if level < LEVELS - 1 && !entry.is_unused() && !entry.is_leaf() && !entry.flags().contains(SHARED_TABLE) {
+                let frame = entry.frame::<PhysAddrSv39>();
+                Self::free_subtables(frame.as_kernel_mut(linear_offset), level + 1, linear_offset, deallocator);
+                deallocator.dealloc(frame);
+            }
         }
     }
 }
 
 
-impl<'a, V, FL> Mapper for Rv39PageTableWith<'a, V, FL>
+impl<'a, V, FL, const LEVELS: usize> Mapper for RvPageTableWith<'a, V, FL, LEVELS>
 where
-    V: VirtualAddress + AddressL3 + Clone + AddressX64,
+    V: VirtualAddress + Clone + AddressX64,
     FL: MapperFlushable,
 {
     type P = PhysAddrSv39;
@@ -423,36 +818,55 @@ where
         allocator: &mut impl FrameAllocatorFor<<Self as Mapper>::P>,
     ) -> Result<Self::MapperFlush, MapToError>
     {
-        let p1_table = self.create_p1_if_not_exist(page.p3_index(), page.p2_index(), allocator)?;
-        if !p1_table[page.p1_index()].is_unused() {
+        let p1_table = self.create_table(&page, LEVELS - 1, allocator)?;
+        let entry = &mut p1_table[Self::indices(&page)[LEVELS - 1]];
+        if !entry.is_unused() {
             return Err(MapToError::PageAlreadyMapped);
         }
-        p1_table[page.p1_index()].set(frame, flags);
+        entry.set(frame, flags);
         Ok(Self::MapperFlush::new(page))
     }
 
-    fn unmap(
+    fn map_to_huge(
         &mut self,
         page: <Self as MapperExt>::Page,
-    ) -> Result<(<Self as MapperExt>::Frame, Self::MapperFlush), UnmapError<<Self as Mapper>::P>>
+        frame: <Self as MapperExt>::Frame,
+        size: PageSize,
+        flags: PageTableFlags,
+        allocator: &mut impl FrameAllocatorFor<<Self as Mapper>::P>,
+    ) -> Result<Self::MapperFlush, MapToError>
     {
-        if self.root_table[page.p3_index()].is_unused() {
-            return Err(UnmapError::PageNotMapped);
+        assert!(
+            page.number() % size.pages() == 0 && frame.number() % size.pages() == 0,
+            "{:?} mapping of page {:#x} to frame {:#x} is misaligned",
+            size,
+            page.number(),
+            frame.number()
+        );
+        let level = Self::level_of(size);
+        let table = self.create_table(&page, level, allocator)?;
+        let entry = &mut table[Self::indices(&page)[level]];
+        if !entry.is_unused() {
+            return Err(MapToError::PageAlreadyMapped);
         }
-        let p2_frame = self.root_table[page.p3_index()].frame::<PhysAddrSv39>();
-        let p2_table: &mut PageTableX64 = unsafe { p2_frame.as_kernel_mut(self.linear_offset) };
+        entry.set(frame, flags);
+        Ok(Self::MapperFlush::new(page))
+    }
 
-        if p2_table[page.p2_index()].is_unused() {
+    fn unmap(
+        &mut self,
+        page: <Self as MapperExt>::Page,
+    ) -> Result<(<Self as MapperExt>::Frame, Self::MapperFlush), UnmapError<<Self as Mapper>::P>>
+    {
+        let (entry, size) = self.walk(&page);
+        if !entry.flags().contains(PageTableFlags::VALID) {
             return Err(UnmapError::PageNotMapped);
         }
-        let p1_frame = p2_table[page.p2_index()].frame::<PhysAddrSv39>();
-        let p1_table: &mut PageTableX64 = unsafe { p1_frame.as_kernel_mut(self.linear_offset) };
-        let p1_entry = &mut p1_table[page.p1_index()];
-        if !p1_entry.flags().contains(PageTableFlags::VALID) {
-            return Err(UnmapError::PageNotMapped);
+        if page.number() % size.pages() != 0 {
+            return Err(UnmapError::ParentEntryHugePage);
         }
-        let frame = p1_entry.frame();
-        p1_entry.set_unused();
+        let frame = entry.frame();
+        entry.set_unused();
         Ok((frame, Self::MapperFlush::new(page)))
     }
 
@@ -460,19 +874,414 @@ where
         &mut self,
         page: &<Self as MapperExt>::Page,
     ) -> Result<&mut PageTableEntryX64, FlagUpdateError> {
-        if self.root_table[page.p3_index()].is_unused() {
-            return Err(FlagUpdateError::PageNotMapped);
-        }
-        let p2_frame = self.root_table[page.p3_index()].frame::<PhysAddrSv39>();
-        let p2_table: &mut PageTableX64 = unsafe { p2_frame.as_kernel_mut(self.linear_offset) };
-        if p2_table[page.p2_index()].is_unused() {
-            return Err(FlagUpdateError::PageNotMapped);
+        match self.walk(page) {
+            (entry, PageSize::Size4KiB) => Ok(entry),
+            (entry, _) if entry.is_leaf() => Ok(entry),
+            _ => Err(FlagUpdateError::PageNotMapped),
         }
+    }
 
-        let p1_frame = p2_table[page.p2_index()].frame::<PhysAddrSv39>();
-        let p1_table: &mut PageTableX64 = unsafe { p1_frame.as_kernel_mut(self.linear_offset) };
-        Ok(&mut p1_table[page.p1_index()])
+    fn translate_page(&mut self, page: <Self as MapperExt>::Page) -> Option<<Self as MapperExt>::Frame> {
+        let (entry, size) = self.walk(&page);
+        if entry.is_unused() {
+            return None;
+        }
+        let frame = entry.frame::<PhysAddrSv39>();
+        Some(FrameWith::of_ppn(frame.number() + page.number() % size.pages()))
     }
 }
 
-pub type Rv39PageTable<'a> = Rv39PageTableWith<'a, VirtAddrSv39, MapperFlush>;
\ No newline at end of file
+pub type Rv39PageTable<'a> = Rv39PageTableWith<'a, VirtAddrSv39, MapperFlush>;
+pub type Rv48PageTable<'a> = Rv48PageTableWith<'a, VirtAddrSv48, MapperFlush>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+    use proptest::prelude::*;
+    use std::collections::btree_map::{BTreeMap, Entry};
+    use std::collections::BTreeSet;
+
+    #[repr(C, align(4096))]
+    struct PhysPage([u8; 4096]);
+
+    /// Simulated physical memory: page `i` of a host buffer is the frame at `base + i * 4096`,
+    /// reachable by the page table through `linear_offset`, just like the kernel's physical
+    /// memory window.
+    struct PhysArena {
+        pages: Vec<PhysPage>,
+        base: usize,
+        next: usize,
+        freed: Vec<Frame>,
+    }
+
+    impl PhysArena {
+        fn new(frames: usize, base: usize) -> Self {
+            PhysArena {
+                pages: (0..frames).map(|_| PhysPage([0xcc; 4096])).collect(),
+                base,
+                next: 0,
+                freed: Vec::new(),
+            }
+        }
+
+        fn linear_offset(&self) -> usize {
+            (self.pages.as_ptr() as usize).wrapping_sub(self.base)
+        }
+
+        /// Frames currently allocated from the arena.
+        fn allocated(&self) -> usize {
+            self.next - self.freed.len()
+        }
+
+        /// Builds a page table whose root is a freshly allocated frame of the arena.
+        fn table<'a, V, const LEVELS: usize>(&mut self) -> RvPageTableWith<'a, V, NoFlush, LEVELS>
+        where
+            V: VirtualAddress + Clone + AddressX64,
+        {
+            let root = FrameAllocator::alloc(self).unwrap();
+            let table: &mut PageTableX64 = unsafe { root.as_kernel_mut(self.linear_offset() as u64) };
+            table.zero();
+            RvPageTableWith::new(table, self.linear_offset())
+        }
+
+        fn page_table<'a>(&mut self) -> Rv39PageTableWith<'a, VirtAddrSv39, NoFlush> {
+            self.table()
+        }
+    }
+
+    impl FrameAllocator for PhysArena {
+        fn alloc(&mut self) -> Option<Frame> {
+            if let Some(frame) = self.freed.pop() {
+                return Some(frame);
+            }
+            if self.next == self.pages.len() {
+                return None;
+            }
+            self.next += 1;
+            Some(Frame::of_addr(PhysAddr::new(self.base + (self.next - 1) * 4096)))
+        }
+    }
+
+    impl FrameDeallocator for PhysArena {
+        fn dealloc(&mut self, frame: Frame) {
+            assert!(!self.freed.contains(&frame), "frame {:#x} freed twice", frame.number());
+            self.freed.push(frame);
+        }
+    }
+
+    /// TLB flushes are meaningless on the host.
+    struct NoFlush;
+
+    impl MapperFlushable for NoFlush {
+        fn new<T: VirtualAddress + Clone + AddressX64>(_page: PageWith<T>) -> Self {
+            NoFlush
+        }
+        fn flush(self) {}
+        fn ignore(self) {}
+    }
+
+    fn page(p3: usize, p2: usize, p1: usize) -> Page {
+        Page::from_page_table_indices(p3, p2, p1)
+    }
+
+    fn frame(ppn: usize) -> Frame {
+        Frame::of_ppn(ppn)
+    }
+
+    const RW: PageTableFlags = PageTableFlags::from_bits_truncate(0b111);
+
+    #[test]
+    fn map_translate_unmap() {
+        let mut arena = PhysArena::new(8, 0x8000_0000);
+        let mut table = arena.page_table();
+        let p = page(511, 3, 7);
+        table.map_to(p, frame(0x80123), RW, &mut arena).unwrap().flush();
+        // Root, one second-level and one leaf table.
+        assert_eq!(arena.allocated(), 3);
+        assert_eq!(table.translate_page(p), Some(frame(0x80123)));
+        assert_eq!(table.translate_page(page(511, 3, 8)), None);
+        assert!(matches!(
+            table.map_to(p, frame(0x80124), RW, &mut arena),
+            Err(MapToError::PageAlreadyMapped)
+        ));
+        let (old, flush) = table.unmap(p).unwrap();
+        flush.flush();
+        assert_eq!(old, frame(0x80123));
+        assert_eq!(table.translate_page(p), None);
+        assert!(matches!(table.unmap(p), Err(UnmapError::PageNotMapped)));
+    }
+
+    #[test]
+    fn map_fails_when_no_frame_for_tables() {
+        let mut arena = PhysArena::new(2, 0x1000);
+        let mut table = arena.page_table();
+        assert!(matches!(
+            table.map_to(page(0, 0, 0), frame(0x42), RW, &mut arena),
+            Err(MapToError::FrameAllocationFailed)
+        ));
+    }
+
+    #[test]
+    fn update_flags_keeps_frame() {
+        let mut arena = PhysArena::new(8, 0x8000_0000);
+        let mut table = arena.page_table();
+        let p = page(2, 0, 1);
+        table.map_to(p, frame(0x80200), RW, &mut arena).unwrap().flush();
+        let flags = PageTableFlags::VALID | PageTableFlags::READABLE | PageTableFlags::USER;
+        table.update_flags(p, flags).unwrap().flush();
+        let entry = table.ref_entry(&p).unwrap();
+        assert_eq!(entry.frame::<PhysAddrSv39>(), frame(0x80200));
+        assert!(entry.flags().contains(flags));
+        assert!(!entry.flags().contains(PageTableFlags::WRITABLE));
+    }
+
+    #[test]
+    fn huge_pages_translate_and_unmap_as_a_whole() {
+        let mut arena = PhysArena::new(8, 0x8000_0000);
+        let mut table = arena.page_table();
+        table.map_to_huge(page(1, 0, 0), frame(0x80000), PageSize::Size1GiB, RW, &mut arena).unwrap().flush();
+        table.map_to_huge(page(2, 4, 0), frame(0x80400), PageSize::Size2MiB, RW, &mut arena).unwrap().flush();
+        // Root plus the level-2 table holding the megapage.
+        assert_eq!(arena.allocated(), 2);
+        assert_eq!(table.translate_page(page(1, 3, 5)), Some(frame(0x80000 + 3 * 512 + 5)));
+        assert_eq!(table.translate_page(page(2, 4, 511)), Some(frame(0x80400 + 511)));
+        assert_eq!(table.translate_page(page(2, 5, 0)), None);
+
+        assert!(matches!(
+            table.map_to(page(1, 3, 5), frame(0x42), RW, &mut arena),
+            Err(MapToError::ParentEntryHugePage)
+        ));
+        assert!(matches!(
+            table.map_to_huge(page(2, 0, 0), frame(0x40000), PageSize::Size1GiB, RW, &mut arena),
+            Err(MapToError::PageAlreadyMapped)
+        ));
+        assert!(matches!(table.unmap(page(2, 4, 1)), Err(UnmapError::ParentEntryHugePage)));
+        let (old, flush) = table.unmap(page(2, 4, 0)).unwrap();
+        flush.flush();
+        assert_eq!(old, frame(0x80400));
+        assert_eq!(table.translate_page(page(2, 4, 1)), None);
+        let (old, flush) = table.unmap(page(1, 0, 0)).unwrap();
+        flush.flush();
+        assert_eq!(old, frame(0x80000));
+        assert_eq!(table.translate_page(page(1, 3, 5)), None);
+    }
+
+    #[test]
+    fn free_empty_tables_stops_at_used_tables() {
+        let mut arena = PhysArena::new(8, 0x8000_0000);
+        let mut table = arena.page_table();
+        for p in [page(3, 1, 0), page(3, 1, 1), page(3, 2, 0)] {
+            table.map_to(p, frame(0x80300), RW, &mut arena).unwrap().flush();
+        }
+        assert_eq!(arena.allocated(), 4);
+        table.unmap(page(3, 1, 0)).unwrap().1.flush();
+        assert_eq!(table.free_empty_tables(&page(3, 1, 0), &mut arena), 0);
+        table.unmap(page(3, 1, 1)).unwrap().1.flush();
+        // Only the leaf table goes; the level-2 table still holds page(3, 2, 0).
+        assert_eq!(table.free_empty_tables(&page(3, 1, 1), &mut arena), 1);
+        assert_eq!(table.translate_page(page(3, 2, 0)), Some(frame(0x80300)));
+        table.unmap(page(3, 2, 0)).unwrap().1.flush();
+        assert_eq!(table.free_empty_tables(&page(3, 2, 0), &mut arena), 2);
+        assert_eq!(arena.allocated(), 1);
+    }
+
+    #[test]
+    fn free_tables_leaves_only_the_root() {
+        let mut arena = PhysArena::new(8, 0x8000_0000);
+        let mut table = arena.page_table();
+        table.map_to(page(0, 0, 0), frame(0x80000), RW, &mut arena).unwrap().flush();
+        table.map_to(page(0, 7, 3), frame(0x80001), RW, &mut arena).unwrap().flush();
+        table.map_to_huge(page(5, 2, 0), frame(0x80200), PageSize::Size2MiB, RW, &mut arena).unwrap().flush();
+        table.map_to_huge(page(6, 0, 0), frame(0x80000), PageSize::Size1GiB, RW, &mut arena).unwrap().flush();
+        assert_eq!(arena.allocated(), 5);
+        table.free_tables(&mut arena);
+        assert_eq!(arena.allocated(), 1);
+        assert_eq!(table.translate_page(page(0, 7, 3)), None);
+        assert_eq!(table.translate_page(page(6, 0, 0)), None);
+    }
+
+    #[test]
+    fn shared_tables_are_seen_by_both_and_never_freed() {
+        let mut arena = PhysArena::new(8, 0x8000_0000);
+        let mut a = arena.page_table();
+        let mut b = arena.page_table();
+        let shared = FrameAllocator::alloc(&mut arena).unwrap();
+        unsafe { shared.as_kernel_mut::<PageTableX64>(arena.linear_offset() as u64) }.zero();
+        a.link_shared_table(&page(5, 0, 0), shared, &mut arena).unwrap();
+        b.link_shared_table(&page(5, 0, 0), shared, &mut arena).unwrap();
+        assert!(matches!(
+            b.link_shared_table(&page(5, 3, 0), shared, &mut arena),
+            Err(MapToError::PageAlreadyMapped)
+        ));
+        a.map_to(page(5, 1, 2), frame(0x80300), RW, &mut arena).unwrap().flush();
+        assert_eq!(b.translate_page(page(5, 1, 2)), Some(frame(0x80300)));
+        assert_eq!(arena.allocated(), 4);
+        b.unmap(page(5, 1, 2)).unwrap().1.flush();
+        assert_eq!(a.translate_page(page(5, 1, 2)), None);
+        // The leaf table goes, the shared table stays even though it is empty.
+        assert_eq!(b.free_empty_tables(&page(5, 1, 2), &mut arena), 1);
+        a.free_tables(&mut arena);
+        b.free_tables(&mut arena);
+        assert_eq!(arena.allocated(), 3);
+    }
+
+    #[test]
+    fn mappings_merge_contiguous_runs() {
+        let mut arena = PhysArena::new(8, 0x8000_0000);
+        let mut table = arena.page_table();
+        let ro = PageTableFlags::VALID | PageTableFlags::READABLE;
+        // Three pages straddling a level-1 table boundary, then one that breaks the frame run
+        // and one with other flags.
+        table.map_to(page(0, 0, 510), frame(0x80000), RW, &mut arena).unwrap().flush();
+        table.map_to(page(0, 0, 511), frame(0x80001), RW, &mut arena).unwrap().flush();
+        table.map_to(page(0, 1, 0), frame(0x80002), RW, &mut arena).unwrap().flush();
+        table.map_to(page(0, 1, 1), frame(0x90000), RW, &mut arena).unwrap().flush();
+        table.map_to(page(0, 1, 2), frame(0x90001), ro, &mut arena).unwrap().flush();
+        table.map_to_huge(page(511, 0, 0), frame(0x80000), PageSize::Size1GiB, RW, &mut arena).unwrap().flush();
+        let runs: Vec<_> = table.mappings().map(|run| (run.va, run.pa, run.len, run.size)).collect();
+        assert_eq!(
+            runs,
+            [
+                (510 << 12, 0x8000_0000, 3 << 12, PageSize::Size4KiB),
+                (513 << 12, 0x9000_0000, 1 << 12, PageSize::Size4KiB),
+                (514 << 12, 0x9000_1000, 1 << 12, PageSize::Size4KiB),
+                (0xffff_ffff_c000_0000, 0x8000_0000, 1 << 30, PageSize::Size1GiB),
+            ]
+        );
+        let last = table.mappings().last().unwrap();
+        assert_eq!(
+            alloc::format!("{}", last),
+            "0xffffffffc0000000-0xffffffffffffffff -> 0x80000000-0xbfffffff VRW---AD Size1GiB x 1"
+        );
+    }
+
+    #[test]
+    fn sv48_maps_through_four_levels() {
+        let mut arena = PhysArena::new(8, 0x8000_0000);
+        let mut table: Rv48PageTableWith<VirtAddrSv48, NoFlush> = arena.table();
+        let page48 = |p4, p3, p2, p1| PageWith::<VirtAddrSv48>::from_page_table_indices(p4, p3, p2, p1);
+        let p = page48(511, 511, 3, 7);
+        table.map_to(p, frame(0x80123), RW, &mut arena).unwrap().flush();
+        assert_eq!(arena.allocated(), 4);
+        assert_eq!(p.start_address().as_usize(), 0xffff_ffff_c060_7000);
+        assert_eq!(table.translate_page(p), Some(frame(0x80123)));
+        assert_eq!(table.translate_page(page48(510, 511, 3, 7)), None);
+        table.map_to_huge(page48(1, 0, 0, 0), frame(0), PageSize::Size512GiB, RW, &mut arena).unwrap().flush();
+        assert_eq!(table.translate_page(page48(1, 5, 3, 2)), Some(frame((5 << 18) + (3 << 9) + 2)));
+        let runs: Vec<_> = table.mappings().map(|run| (run.va, run.pa, run.size)).collect();
+        assert_eq!(
+            runs,
+            [(1 << 39, 0, PageSize::Size512GiB), (0xffff_ffff_c060_7000, 0x8012_3000, PageSize::Size4KiB)]
+        );
+        table.unmap(p).unwrap().1.flush();
+        assert_eq!(table.free_empty_tables(&p, &mut arena), 3);
+        table.free_tables(&mut arena);
+        assert_eq!(arena.allocated(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "need more than 3 levels")]
+    fn sv39_has_no_terapages() {
+        let mut arena = PhysArena::new(8, 0x8000_0000);
+        let mut table = arena.page_table();
+        let _ = table.map_to_huge(page(0, 0, 0), frame(0), PageSize::Size512GiB, RW, &mut arena);
+    }
+
+    #[test]
+    #[should_panic(expected = "misaligned")]
+    fn huge_page_must_be_aligned() {
+        let mut arena = PhysArena::new(8, 0x8000_0000);
+        let mut table = arena.page_table();
+        let _ = table.map_to_huge(page(0, 1, 0), frame(0x80001), PageSize::Size2MiB, RW, &mut arena);
+    }
+
+    #[test]
+    fn flush_batch_falls_back_to_flushing_everything() {
+        let mut batch = FlushBatch::<2>::new();
+        batch.push(MapperFlush::new(page(0, 0, 1)));
+        batch.push(MapperFlush::new(page(0, 0, 2)));
+        assert_eq!(batch.pages(), Some(&[0x1000, 0x2000][..]));
+        batch.push(MapperFlush::new(page(0, 0, 3)));
+        assert_eq!(batch.pages(), None);
+    }
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        Map { page: (usize, usize, usize), ppn: usize },
+        Unmap { page: (usize, usize, usize) },
+        Translate { page: (usize, usize, usize) },
+    }
+
+    /// Pages crowded into a few tables, so mappings share intermediate tables.
+    fn indices() -> impl Strategy<Value = (usize, usize, usize)> {
+        (prop_oneof![Just(0usize), Just(1), Just(256), Just(511)], 0usize..3, 0usize..8)
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            (indices(), 0x80000usize..0x88000).prop_map(|(page, ppn)| Op::Map { page, ppn }),
+            indices().prop_map(|page| Op::Unmap { page }),
+            indices().prop_map(|page| Op::Translate { page }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn matches_model(
+            base in prop_oneof![Just(0usize), Just(0x8000_0000), Just(0x20_0000_0000)],
+            ops in prop::collection::vec(op(), 1..300),
+            reclaim in any::<bool>(),
+        ) {
+            let mut arena = PhysArena::new(64, base);
+            let mut table = arena.page_table();
+            let mut model = BTreeMap::new();
+            for op in ops {
+                match op {
+                    Op::Map { page: (p3, p2, p1), ppn } => {
+                        let result = table.map_to(page(p3, p2, p1), frame(ppn), RW, &mut arena);
+                        match model.entry((p3, p2, p1)) {
+                            Entry::Occupied(_) => {
+                                prop_assert!(matches!(result, Err(MapToError::PageAlreadyMapped)))
+                            }
+                            Entry::Vacant(entry) => {
+                                result.unwrap().flush();
+                                entry.insert(ppn);
+                            }
+                        }
+                    }
+                    Op::Unmap { page: (p3, p2, p1) } => {
+                        match (table.unmap(page(p3, p2, p1)), model.remove(&(p3, p2, p1))) {
+                            (Ok((old, flush)), Some(ppn)) => {
+                                flush.flush();
+                                prop_assert_eq!(old, frame(ppn));
+                                if reclaim {
+                                    table.free_empty_tables(&page(p3, p2, p1), &mut arena);
+                                }
+                            }
+                            (Err(UnmapError::PageNotMapped), None) => {}
+                            (result, expected) => {
+                                prop_assert!(false, "unmap gave {:?}, expected {:?}", result.map(|r| r.0), expected)
+                            }
+                        }
+                    }
+                    Op::Translate { page: (p3, p2, p1) } => {
+                        let expected = model.get(&(p3, p2, p1)).map(|&ppn| frame(ppn));
+                        prop_assert_eq!(table.translate_page(page(p3, p2, p1)), expected);
+                    }
+                }
+            }
+            for (&(p3, p2, p1), &ppn) in model.iter() {
+                prop_assert_eq!(table.translate_page(page(p3, p2, p1)), Some(frame(ppn)));
+            }
+            if reclaim {
+                // Exactly the root and the tables some mapping still goes through are left.
+                let p2_tables: BTreeSet<_> = model.keys().map(|&(p3, _, _)| p3).collect();
+                let p1_tables: BTreeSet<_> = model.keys().map(|&(p3, p2, _)| (p3, p2)).collect();
+                prop_assert_eq!(arena.allocated(), 1 + p2_tables.len() + p1_tables.len());
+            }
+            table.free_tables(&mut arena);
+            prop_assert_eq!(arena.allocated(), 1);
+        }
+    }
+}
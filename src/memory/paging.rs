@@ -2,12 +2,19 @@ use crate::alloc_frame;
 use crate::consts::*;
 use crate::address::*;
 use crate::dealloc_frame;
+use crate::{frame_refs, release_frame, share_frame};
+use crate::frame_tracker::FrameTracker;
 use crate::page_table::PageTableEntry;
 use crate::page_table::PageTableFlags as EF;
 use crate::page_table::PTE;
 use crate::page_table::*;
 
 use crate::register::*;
+use crate::memory::memory_set::attr::MemoryAttr;
+use crate::memory::asid::{Asid, ASID_ALLOCATOR};
+use crate::utils::mutex::Mutex;
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, Ordering};
 pub fn access_pa_via_va(paddr: usize) -> usize {
     paddr + PHYSICAL_MEMORY_OFFSET
 }
@@ -23,26 +30,48 @@ impl PageTableEntryArray {
     }
 }
 
-// 定义内联汇编函数
-#[inline(always)]
-unsafe fn sfence_vma(asid: usize, va: usize) {
-    core::arch::asm!("sfence.vma {0}, {1}", in(reg) asid, in(reg) va);
-}
-
-#[inline(always)]
-unsafe fn sfence_vma_all() {
-    core::arch::asm!("sfence.vma zero, zero");
-}
-
-pub struct PageEntry(&'static mut PageTableEntry, Page);
+/// A leaf entry of a `PageTableImpl`, the virtual address it was looked up with and the
+/// ASID of the page table.
+pub struct PageEntry(&'static mut PageTableEntry, usize, usize);
 
 impl PageEntry {
     pub fn update(&mut self) {
         unsafe {
-            sfence_vma(0, self.1.start_address().as_usize());
+            sfence_vma(self.2, self.1);
         }
     }
 
+    /// Like `update`, for entries kept past the borrow of their page table, whose ASID may
+    /// have changed since.
+    pub fn update_all_asids(&mut self) {
+        unsafe {
+            sfence_vma_all_asids(self.1);
+        }
+    }
+
+    /// A second handle to the same entry that does not borrow the page table.
+    ///
+    /// # Safety
+    ///
+    /// The page table must neither be dropped nor unmap the page while the handle is used.
+    pub unsafe fn detach(&mut self) -> PageEntry {
+        PageEntry(&mut *(self.0 as *mut PageTableEntry), self.1, self.2)
+    }
+
+    /// Whether both handles refer to the same entry.
+    pub fn is_same(&self, other: &PageEntry) -> bool {
+        core::ptr::eq(&*self.0, &*other.0)
+    }
+
+    pub fn va(&self) -> usize {
+        self.1
+    }
+
+    /// Clears the entry, whatever it holds.
+    pub fn clear(&mut self) {
+        self.0.set_unused();
+    }
+
     pub fn accessed(&self) -> bool { self.0.flags().contains(EF::ACCESSED) }
     pub fn clear_accessed(&mut self) { self.0.flags_mut().remove(EF::ACCESSED); }
 
@@ -67,6 +96,45 @@ impl PageEntry {
         self.0.flags_mut().set(EF::EXECUTABLE, value);
     }
 
+    /// The frame is shared read-only with other page tables.
+    pub fn readonly_shared(&self) -> bool { self.0.flags().contains(EF::RESERVED1) }
+    pub fn set_readonly_shared(&mut self, value: bool) {
+        self.0.flags_mut().set(EF::RESERVED1, value);
+    }
+
+    /// The frame is shared copy-on-write: the page is writable, but gets a private copy of the
+    /// frame on the first store.
+    pub fn writable_shared(&self) -> bool { self.0.flags().contains(EF::RESERVED2) }
+    pub fn set_writable_shared(&mut self, value: bool) {
+        self.0.flags_mut().set(EF::RESERVED2, value);
+    }
+
+    /// The page is swapped out: the entry is not valid and holds a swap slot instead of a
+    /// frame. `RESERVED1` is only read as `readonly_shared` on valid entries.
+    pub fn swapped(&self) -> bool {
+        let flags = self.0.flags();
+        !flags.contains(EF::VALID) && flags.contains(EF::RESERVED1)
+    }
+
+    pub fn swap_slot(&self) -> usize {
+        self.0.ppn()
+    }
+
+    /// Marks the page swapped out to `slot`, keeping its permissions for `set_swapped_in`.
+    pub fn set_swapped_out(&mut self, slot: usize) {
+        let flags = (self.0.flags() - EF::VALID - EF::READABLE) | EF::RESERVED1;
+        self.0.set(Frame::of_ppn(slot), flags);
+    }
+
+    /// Maps the swapped out page to `target` again. The page starts out clean, so the dirty
+    /// bit tells whether it was written since it was read back.
+    pub fn set_swapped_in(&mut self, target: usize) {
+        self.0.flags_mut().remove(EF::RESERVED1);
+        self.0.set_ppn(target / PAGE_SIZE);
+        self.clear_dirty();
+        self.set_present(true);
+    }
+
     pub fn target(&self) -> usize {
         self.0.addr::<PhysAddrSv39>().as_usize()
     }
@@ -78,73 +146,462 @@ impl PageEntry {
 }
 
 struct FrameAllocatorForPaging;
-pub trait FrameAllocator {
-    fn alloc(&mut self) -> Option<Frame>;
-}
 impl FrameAllocator for FrameAllocatorForPaging {
     fn alloc(&mut self) -> Option<Frame> {
         alloc_frame()
     }
 }
-pub trait FrameDeallocator {
-    fn dealloc(&mut self, frame: Frame);
-}
 impl FrameDeallocator for FrameAllocatorForPaging {
     fn dealloc(&mut self, frame: Frame) {
         dealloc_frame(frame)
     }
 }
 
+/// How virtual addresses are translated: three levels of page tables or four.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PagingMode {
+    Sv39,
+    Sv48,
+}
+
+impl PagingMode {
+    /// The MODE field of `satp`.
+    fn satp_mode(self) -> usize {
+        match self {
+            PagingMode::Sv39 => 8,
+            PagingMode::Sv48 => 9,
+        }
+    }
+}
+
+static SV48: AtomicBool = AtomicBool::new(false);
+
+/// Chooses the paging mode of the page tables created from now on. Called once at boot,
+/// before the kernel is remapped.
+pub fn set_paging_mode(mode: PagingMode) {
+    SV48.store(mode == PagingMode::Sv48, Ordering::Relaxed);
+}
+
+pub fn paging_mode() -> PagingMode {
+    if SV48.load(Ordering::Relaxed) {
+        PagingMode::Sv48
+    } else {
+        PagingMode::Sv39
+    }
+}
+
+/// Finds out how many ASID bits the hardware implements by writing ones to the ASID field
+/// of `satp` and reading them back, and hands them to the ASID allocator.
+pub fn init_asids() {
+    let token = PageTableImpl::active_token();
+    let bits = unsafe {
+        PageTableImpl::set_token(token | (0xffff << 44));
+        let bits = ((PageTableImpl::active_token() >> 44) & 0xffff).count_ones() as usize;
+        PageTableImpl::set_token(token);
+        bits
+    };
+    PageTableImpl::flush_tlb();
+    ASID_ALLOCATOR.lock().init(bits);
+    println!("ASID bits: {}", bits);
+}
+
+const _: () = assert!(KERNEL_SHARED_BASE.is_multiple_of(1 << 30) && KERNEL_SHARED_END - KERNEL_SHARED_BASE == 1 << 30);
+
+/// The table of 2 MiB entries behind `[KERNEL_SHARED_BASE, KERNEL_SHARED_END)`, allocated
+/// by the first page table that links it and never freed.
+static KERNEL_SHARED_TABLE: Mutex<Option<Frame>> = Mutex::new(None);
+
+/// What `PageTableImpl` needs from a page table, whatever its number of levels.
+trait RvPageTable {
+    fn map_to(&mut self, va: usize, pa: usize, size: PageSize, flags: EF) -> Result<MapperFlush, MapToError>;
+    fn unmap(&mut self, va: usize) -> Result<MapperFlush, UnmapError<PhysAddr>>;
+    fn entry(&mut self, va: usize) -> Option<&mut PageTableEntry>;
+    fn update_flags(&mut self, va: usize, flags: EF) -> Result<MapperFlush, FlagUpdateError>;
+    fn free_empty_tables(&mut self, va: usize) -> usize;
+    fn link_shared_table(&mut self, va: usize, table: Frame) -> Result<(), MapToError>;
+}
+
+impl<V, const LEVELS: usize> RvPageTable for RvPageTableWith<'static, V, MapperFlush, LEVELS>
+where
+    V: VirtualAddress + Clone + AddressX64,
+{
+    fn map_to(&mut self, va: usize, pa: usize, size: PageSize, flags: EF) -> Result<MapperFlush, MapToError> {
+        let page = PageWith::of_addr(V::new(va));
+        let frame = Frame::of_addr(PhysAddr::new(pa));
+        self.map_to_huge(page, frame, size, flags, &mut FrameAllocatorForPaging)
+    }
+
+    fn unmap(&mut self, va: usize) -> Result<MapperFlush, UnmapError<PhysAddr>> {
+        let (_, flush) = Mapper::unmap(self, PageWith::of_addr(V::new(va)))?;
+        Ok(flush)
+    }
+
+    fn entry(&mut self, va: usize) -> Option<&mut PageTableEntry> {
+        self.ref_entry(&PageWith::of_addr(V::new(va))).ok()
+    }
+
+    fn update_flags(&mut self, va: usize, flags: EF) -> Result<MapperFlush, FlagUpdateError> {
+        Mapper::update_flags(self, PageWith::of_addr(V::new(va)), flags)
+    }
+
+    fn free_empty_tables(&mut self, va: usize) -> usize {
+        RvPageTableWith::free_empty_tables(self, &PageWith::of_addr(V::new(va)), &mut FrameAllocatorForPaging)
+    }
+
+    fn link_shared_table(&mut self, va: usize, table: Frame) -> Result<(), MapToError> {
+        let page = PageWith::of_addr(V::new(va));
+        RvPageTableWith::link_shared_table(self, &page, table, &mut FrameAllocatorForPaging)
+    }
+}
+
+enum PageTableKind {
+    Sv39(Rv39PageTable<'static>),
+    Sv48(Rv48PageTable<'static>),
+}
+
 pub struct PageTableImpl {
-    page_table: Rv39PageTable<'static>,
-    root_frame: Frame,
+    page_table: PageTableKind,
+    root_frame: FrameTracker,
     entry: Option<PageEntry>,
+    free_empty_tables: bool,
+    asid: Cell<Asid>,
 }
 
 impl PageTableImpl {
-    pub fn new_bare() -> Self {
-        let frame = alloc_frame().expect("alloc_frame failed!");
+    /// An empty page table in the mode chosen by `set_paging_mode`, or `None` if there is no
+    /// frame left for its root.
+    pub fn new_bare() -> Option<Self> {
+        let frame = FrameTracker::new()?;
         let paddr = frame.start_address().as_usize();
         let table = unsafe { &mut *(access_pa_via_va(paddr) as *mut PageTableEntryArray) };
-        table.zero();
         let page_table: &mut PageTableWith<Entries64, PageTableEntryX64> = unsafe {
             &mut *(table as *mut _ as *mut PageTableWith<Entries64, PageTableEntryX64>)
         };
-        PageTableImpl {
-            page_table: Rv39PageTable::new(page_table, PHYSICAL_MEMORY_OFFSET),
+        let page_table = match paging_mode() {
+            PagingMode::Sv39 => PageTableKind::Sv39(Rv39PageTable::new(page_table, PHYSICAL_MEMORY_OFFSET)),
+            PagingMode::Sv48 => PageTableKind::Sv48(Rv48PageTable::new(page_table, PHYSICAL_MEMORY_OFFSET)),
+        };
+        Some(PageTableImpl {
+            page_table,
             root_frame: frame,
-            entry: None
+            entry: None,
+            free_empty_tables: false,
+            asid: Cell::new(Asid::INVALID),
+        })
+    }
+
+    fn table(&mut self) -> &mut dyn RvPageTable {
+        match &mut self.page_table {
+            PageTableKind::Sv39(table) => table,
+            PageTableKind::Sv48(table) => table,
         }
     }
 
-    pub fn map(&mut self, va: usize, pa: usize) -> &mut PageEntry {
+    pub fn mode(&self) -> PagingMode {
+        match self.page_table {
+            PageTableKind::Sv39(_) => PagingMode::Sv39,
+            PageTableKind::Sv48(_) => PagingMode::Sv48,
+        }
+    }
+
+    /// Whether `unmap` frees the page tables it leaves empty. Off by default, since tables
+    /// freed this way are often allocated again by the next mapping nearby.
+    pub fn set_free_empty_tables(&mut self, value: bool) {
+        self.free_empty_tables = value;
+    }
+
+    /// Links the table every address space shares for `[KERNEL_SHARED_BASE,
+    /// KERNEL_SHARED_END)`, allocating it for the first page table that asks.
+    pub fn link_kernel_shared(&mut self) -> Result<(), MapToError> {
+        let mut shared = KERNEL_SHARED_TABLE.lock();
+        let table = match *shared {
+            Some(table) => table,
+            None => {
+                let table = alloc_frame().ok_or(MapToError::FrameAllocationFailed)?;
+                let entries = access_pa_via_va(table.start_address().as_usize()) as *mut PageTableEntryArray;
+                unsafe { (*entries).zero() };
+                *shared = Some(table);
+                table
+            }
+        };
+        self.table().link_shared_table(KERNEL_SHARED_BASE, table)
+    }
+
+    /// Maps `va` to `pa`, failing with `MapToError::FrameAllocationFailed` when an
+    /// intermediate page table cannot be allocated.
+    pub fn map(&mut self, va: usize, pa: usize) -> Result<&mut PageEntry, MapToError> {
+        self.map_huge(va, pa, PageSize::Size4KiB)
+    }
+
+    /// Maps the `size` page at `va` to the `size` frame at `pa`, like `map`.
+    pub fn map_huge(&mut self, va: usize, pa: usize, size: PageSize) -> Result<&mut PageEntry, MapToError> {
         let flags = EF::VALID | EF::READABLE | EF::WRITABLE;
-        let page = Page::of_addr(VirtAddr::new(va));
-        let frame = Frame::of_addr(PhysAddr::new(pa));
-        self.page_table
-            .map_to(page, frame, flags, &mut FrameAllocatorForPaging)
-            .unwrap()
-            .flush();
-        self.get_entry(va).expect("fail to get an entry!")
+        let asid = self.asid();
+        self.table().map_to(va, pa, size, flags)?.flush_asid(asid);
+        Ok(self.get_entry(va).expect("fail to get an entry!"))
+    }
+
+    /// Splits `[start, end)`, mapped to the physical memory at `va - offset`, into the largest
+    /// pages the alignment of each piece allows.
+    pub fn linear_pages(start: usize, end: usize, offset: usize) -> impl Iterator<Item = (usize, PageSize)> {
+        let mut va = start & !(PAGE_SIZE - 1);
+        core::iter::from_fn(move || {
+            if va >= end {
+                return None;
+            }
+            let pa = va.wrapping_sub(offset);
+            let size = [PageSize::Size1GiB, PageSize::Size2MiB, PageSize::Size4KiB]
+                .into_iter()
+                .find(|size| pa.is_multiple_of(size.bytes()) && va.is_multiple_of(size.bytes()) && va + size.bytes() <= end)
+                .unwrap_or(PageSize::Size4KiB);
+            let page = (va, size);
+            va += size.bytes();
+            Some(page)
+        })
+    }
+
+    /// Maps physical memory `[start, end)` into the linear window at `PHYSICAL_MEMORY_OFFSET`,
+    /// using the largest pages the alignment of each piece allows.
+    pub fn map_linear(&mut self, start: usize, end: usize, flags: EF) -> Result<(), MapToError> {
+        let (start, end) = (access_pa_via_va(start), access_pa_via_va(end));
+        let asid = self.asid();
+        for (va, size) in Self::linear_pages(start, end, PHYSICAL_MEMORY_OFFSET) {
+            self.table().map_to(va, va - PHYSICAL_MEMORY_OFFSET, size, flags)?.flush_asid(asid);
+        }
+        Ok(())
     }
 
     pub fn unmap(&mut self, va: usize) {
-        let page = Page::of_addr(VirtAddr::new(va));
-        let (_, flush) = self.page_table.unmap(page).unwrap();
-        flush.flush();
+        let asid = self.asid();
+        self.table().unmap(va).unwrap().flush_asid(asid);
+        if self.free_empty_tables && self.table().free_empty_tables(va) > 0 {
+            unsafe { Self::flush_freed_tables(asid, is_kernel_shared(va)) };
+        }
+    }
+
+    /// Flushes the address space tagged with `asid` after freeing some of its tables, or every
+    /// address space if the tables were `shared` by all of them.
+    unsafe fn flush_freed_tables(asid: usize, shared: bool) {
+        if shared {
+            sfence_vma_all();
+        } else {
+            sfence_vma_asid(asid);
+        }
     }
 
-    fn get_entry(&mut self, va: usize) -> Option<&mut PageEntry> {
-        let page = Page::of_addr(VirtAddr::new(va));
-        if let Ok(e) = self.page_table.ref_entry(page.clone()) {
+    /// Maps every mapped 4 KiB page of `[start, end)` into `dst` to the same frame. Writable
+    /// pages become copy-on-write in both page tables, read-only ones are just shared. Every
+    /// frame gets a reference per page table, see `release_frame`.
+    ///
+    /// If `dst` runs out of page tables, the pages mapped into it so far are unmapped again;
+    /// the pages of `self` stay marked as shared, which costs at most a spurious fault each.
+    pub fn clone_cow(&mut self, dst: &mut PageTableImpl, start: usize, end: usize) -> Result<(), MapToError> {
+        for page in PageRange::new(start, end) {
+            let entry = match self.get_entry(page) {
+                Some(entry) if entry.present() => entry,
+                _ => continue,
+            };
+            if entry.writable() || entry.writable_shared() {
+                entry.set_writable(false);
+                entry.set_writable_shared(true);
+            } else {
+                entry.set_readonly_shared(true);
+            }
+            entry.update();
+            let (pa, user, execute) = (entry.target(), entry.user(), entry.execute());
+            let writable_shared = entry.writable_shared();
+            match dst.map(page, pa) {
+                Ok(entry) => {
+                    entry.set_writable(false);
+                    entry.set_user(user);
+                    entry.set_execute(execute);
+                    entry.set_writable_shared(writable_shared);
+                    entry.set_readonly_shared(!writable_shared);
+                    entry.update();
+                    share_frame(Frame::of_addr(PhysAddr::new(pa)));
+                }
+                Err(e) => {
+                    for mapped in PageRange::new(start, page) {
+                        if let Some(entry) = dst.get_entry(mapped).filter(|entry| entry.present()) {
+                            let pa = entry.target();
+                            dst.unmap(mapped);
+                            release_frame(Frame::of_addr(PhysAddr::new(pa)));
+                        }
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolves a store to the copy-on-write page at `va`: copies its frame, or takes it over
+    /// if no other page table uses it any more. Returns `Ok(false)` if the page is not
+    /// copy-on-write.
+    pub fn copy_on_write(&mut self, va: usize) -> Result<bool, MapToError> {
+        let entry = match self.get_entry(va) {
+            Some(entry) if entry.present() && entry.writable_shared() => entry,
+            _ => return Ok(false),
+        };
+        let frame = Frame::of_addr(PhysAddr::new(entry.target()));
+        if frame_refs(frame) > 1 {
+            let copy = alloc_frame().ok_or(MapToError::FrameAllocationFailed)?;
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    access_pa_via_va(frame.start_address().as_usize()) as *const u8,
+                    access_pa_via_va(copy.start_address().as_usize()) as *mut u8,
+                    PAGE_SIZE,
+                );
+            }
+            entry.set_target(copy.start_address().as_usize());
+            release_frame(frame);
+        }
+        entry.set_writable_shared(false);
+        entry.set_writable(true);
+        entry.update();
+        Ok(true)
+    }
+
+    /// Gives the page at `va` the permissions in `attr`, if anything is mapped there. Shared
+    /// frames stay shared: a page that may now be written becomes copy-on-write instead.
+    pub fn protect(&mut self, va: usize, attr: &MemoryAttr) {
+        let asid = self.asid();
+        if let Some(flush) = self.protect_deferred(va, attr) {
+            flush.flush_asid(asid);
+        }
+    }
+
+    /// `protect`, leaving the TLB flush to the caller.
+    fn protect_deferred(&mut self, va: usize, attr: &MemoryAttr) -> Option<MapperFlush> {
+        let old = match self.table().entry(va) {
+            Some(entry) if !entry.is_unused() => entry.flags(),
+            _ => return None,
+        };
+        let mut flags = old - EF::WRITABLE - EF::EXECUTABLE - EF::USER;
+        flags.set(EF::USER, attr.user());
+        flags.set(EF::EXECUTABLE, attr.execute());
+        let writable = !attr.readonly();
+        if old.contains(EF::VALID) && old.intersects(EF::RESERVED1 | EF::RESERVED2) {
+            flags.remove(EF::RESERVED1 | EF::RESERVED2);
+            flags.insert(if writable { EF::RESERVED2 } else { EF::RESERVED1 });
+        } else {
+            flags.set(EF::WRITABLE, writable);
+        }
+        Some(self.table().update_flags(va, flags).unwrap())
+    }
+
+    /// Maps every page of `range` with the permissions in `attr`, to the frame `target`
+    /// returns for its address, and flushes the TLB once at the end. If a page cannot be
+    /// mapped, the pages mapped so far are unmapped again and the tables allocated for them
+    /// freed.
+    pub fn map_range(
+        &mut self,
+        range: PageRange,
+        attr: &MemoryAttr,
+        mut target: impl FnMut(usize) -> usize,
+    ) -> Result<(), MapToError> {
+        let flags = attr.flags();
+        let asid = self.asid();
+        let mut batch = FlushBatch::<TLB_FLUSH_BATCH>::new();
+        for va in range {
+            match self.table().map_to(va, target(va), PageSize::Size4KiB, flags) {
+                Ok(flush) => batch.push(flush),
+                Err(e) => {
+                    // The tables this call allocated are left empty, so free them whatever
+                    // `set_free_empty_tables` says.
+                    let mut freed = self.table().free_empty_tables(va);
+                    for mapped in range.take_while(|&mapped| mapped < va) {
+                        batch.push(self.table().unmap(mapped).unwrap());
+                        freed += self.table().free_empty_tables(mapped);
+                    }
+                    if freed > 0 {
+                        unsafe { Self::flush_freed_tables(asid, is_kernel_shared(va)) };
+                        batch.ignore();
+                    } else {
+                        batch.flush_asid(asid);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        batch.flush_asid(asid);
+        Ok(())
+    }
+
+    /// Unmaps every mapped page of `range`, flushing the TLB once at the end. Pages that are
+    /// not mapped are skipped, as in `protect_range`.
+    pub fn unmap_range(&mut self, range: PageRange) {
+        let asid = self.asid();
+        let mut batch = FlushBatch::<TLB_FLUSH_BATCH>::new();
+        let mut freed = 0;
+        let mut shared = false;
+        for va in range {
+            match self.table().unmap(va) {
+                Ok(flush) => batch.push(flush),
+                Err(UnmapError::PageNotMapped) => continue,
+                Err(e) => panic!("failed to unmap {:#x}: {:?}", va, e),
+            }
+            if self.free_empty_tables {
+                freed += self.table().free_empty_tables(va);
+                shared |= is_kernel_shared(va);
+            }
+        }
+        if freed > 0 {
+            unsafe { Self::flush_freed_tables(asid, shared) };
+            batch.ignore();
+        } else {
+            batch.flush_asid(asid);
+        }
+    }
+
+    /// `protect` on every page of `range`, flushing the TLB once at the end.
+    pub fn protect_range(&mut self, range: PageRange, attr: &MemoryAttr) {
+        let asid = self.asid();
+        let mut batch = FlushBatch::<TLB_FLUSH_BATCH>::new();
+        for va in range {
+            if let Some(flush) = self.protect_deferred(va, attr) {
+                batch.push(flush);
+            }
+        }
+        batch.flush_asid(asid);
+    }
+
+    pub fn get_entry(&mut self, va: usize) -> Option<&mut PageEntry> {
+        let asid = self.asid();
+        if let Some(e) = self.table().entry(va) {
             let e = unsafe { &mut *(e as *mut PageTableEntry) };
-            self.entry = Some(PageEntry(e, page));
+            self.entry = Some(PageEntry(e, va, asid));
             self.entry.as_mut()
         } else {
             None
         }
     }
-    pub fn token(&self) -> usize { self.root_frame.number() | (8 << 60) }
+    /// The mappings of this page table, see `RvPageTableWith::mappings`.
+    pub fn mappings(&self) -> impl Iterator<Item = MappingRun> + '_ {
+        let (sv39, sv48) = match &self.page_table {
+            PageTableKind::Sv39(table) => (Some(table.mappings()), None),
+            PageTableKind::Sv48(table) => (None, Some(table.mappings())),
+        };
+        sv39.into_iter().flatten().chain(sv48.into_iter().flatten())
+    }
+
+    /// Prints what is mapped where.
+    pub fn dump(&self) {
+        println!("{:?} page table {:#x}:", self.mode(), self.root_frame.start_address().as_usize());
+        for run in self.mappings() {
+            println!("  {}", run);
+        }
+    }
+
+    /// The ASID this page table was last activated with. TLB entries of this address space
+    /// can only be tagged with it.
+    fn asid(&self) -> usize {
+        self.asid.get().value()
+    }
+
+    pub fn token(&self) -> usize {
+        self.root_frame.number() | (self.asid() << 44) | (self.mode().satp_mode() << 60)
+    }
 
     unsafe fn set_token(token: usize) {
         core::arch::asm!("csrw satp, {}", in(reg) token);
@@ -154,17 +611,36 @@ impl PageTableImpl {
 
     fn flush_tlb() { unsafe { sfence_vma_all(); } }
 
+    /// Switches to this page table. Entries of other address spaces stay in the TLB, tagged
+    /// with their ASIDs; the whole TLB is only flushed when the ASIDs run out.
     pub unsafe fn activate(&self) {
+        let (asid, rollover) = ASID_ALLOCATOR.lock().check(self.asid.get());
+        self.asid.set(asid);
         let old_token = Self::active_token();
         let new_token = self.token();
         println!("switch satp from {:#x} to {:#x}", old_token, new_token);
         if new_token != old_token {
             Self::set_token(new_token);
+        }
+        // Flush after the switch: until satp changes, the old address space can still
+        // refill the TLB under an ASID that has just been handed out again.
+        if rollover {
             Self::flush_tlb();
         }
     }
 }
 
+impl Drop for PageTableImpl {
+    /// Frees every table frame; the root goes with `root_frame`. The page table must not be
+    /// active any more.
+    fn drop(&mut self) {
+        match &mut self.page_table {
+            PageTableKind::Sv39(table) => table.free_tables(&mut FrameAllocatorForPaging),
+            PageTableKind::Sv48(table) => table.free_tables(&mut FrameAllocatorForPaging),
+        }
+    }
+}
+
 #[derive(Clone, Copy, PartialEq, Eq)]
 #[repr(C)]
 pub struct PageRange {
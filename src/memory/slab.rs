@@ -0,0 +1,320 @@
+use crate::consts::PAGE_SIZE;
+use crate::linked_list::LinkedList;
+use crate::utils::mutex::Mutex;
+use super::buddy_system_allocator::{Heap, LockedHeapWithRescue};
+use core::alloc::{GlobalAlloc, Layout};
+use core::marker::PhantomData;
+use core::mem::size_of;
+use core::ptr::{self, NonNull};
+
+/// Object sizes of the generic caches. Requests above the largest go to the buddy heap.
+const SIZE_CLASSES: [usize; 12] = [16, 24, 32, 48, 64, 96, 128, 192, 256, 512, 1024, 2048];
+
+pub const MAX_NAMED_CACHES: usize = 8;
+pub const MAX_SLAB_CACHES: usize = SIZE_CLASSES.len() + MAX_NAMED_CACHES;
+
+/// Header at the start of every slab. Slabs are naturally aligned, so the header of any
+/// object is found by masking its address.
+#[repr(C)]
+struct Slab {
+    prev: *mut Slab,
+    next: *mut Slab,
+    free: LinkedList,
+    in_use: usize,
+}
+
+/// A cache of equally sized objects carved out of slabs taken from the buddy heap.
+///
+/// Slabs with free objects are kept on a doubly linked list; full slabs are not tracked
+/// until one of their objects is freed. A slab that becomes empty is given back to the
+/// heap unless it is the only one with free objects left.
+pub struct SlabCache {
+    name: &'static str,
+    size: usize,
+    align: usize,
+    slab_size: usize,
+    partial: *mut Slab,
+    slabs: usize,
+    in_use: usize,
+}
+
+unsafe impl Send for SlabCache {}
+
+/// Per-cache numbers reported by `memory::stats`.
+#[derive(Debug, Clone, Copy)]
+pub struct SlabCacheStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    pub objects_per_slab: usize,
+    pub slabs: usize,
+    pub in_use: usize,
+}
+
+impl SlabCache {
+    const fn new(name: &'static str, size: usize, align: usize) -> Self {
+        // Every free object has to hold an aligned free list link.
+        let size = if size < size_of::<usize>() { size_of::<usize>() } else { size };
+        let align = if align < size_of::<usize>() { size_of::<usize>() } else { align };
+        let size = (size + align - 1) & !(align - 1);
+        // Keep at least eight objects per slab.
+        let slab_size = (size * 8).next_power_of_two();
+        let slab_size = if slab_size < PAGE_SIZE { PAGE_SIZE } else { slab_size };
+        SlabCache {
+            name,
+            size,
+            align,
+            slab_size,
+            partial: ptr::null_mut(),
+            slabs: 0,
+            in_use: 0,
+        }
+    }
+
+    /// Offset of the first object inside a slab.
+    fn first(&self) -> usize {
+        (size_of::<Slab>() + self.align - 1) & !(self.align - 1)
+    }
+
+    fn objects_per_slab(&self) -> usize {
+        (self.slab_size - self.first()) / self.size
+    }
+
+    fn object_layout(&self) -> Layout {
+        Layout::from_size_align(self.size, self.align).unwrap()
+    }
+
+    fn slab_layout(&self) -> Layout {
+        Layout::from_size_align(self.slab_size, self.slab_size).unwrap()
+    }
+
+    fn fits(&self, layout: &Layout) -> bool {
+        layout.size() <= self.size && layout.align() <= self.align
+    }
+
+    unsafe fn link(&mut self, slab: *mut Slab) {
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = self.partial;
+        if !self.partial.is_null() {
+            (*self.partial).prev = slab;
+        }
+        self.partial = slab;
+    }
+
+    unsafe fn unlink(&mut self, slab: *mut Slab) {
+        if (*slab).prev.is_null() {
+            self.partial = (*slab).next;
+        } else {
+            (*(*slab).prev).next = (*slab).next;
+        }
+        if !(*slab).next.is_null() {
+            (*(*slab).next).prev = (*slab).prev;
+        }
+    }
+
+    unsafe fn grow(&mut self, heap: &impl GlobalAlloc) -> bool {
+        let slab = heap.alloc(self.slab_layout()) as *mut Slab;
+        if slab.is_null() {
+            return false;
+        }
+        slab.write(Slab {
+            prev: ptr::null_mut(),
+            next: ptr::null_mut(),
+            free: LinkedList::new(),
+            in_use: 0,
+        });
+        for i in (0..self.objects_per_slab()).rev() {
+            (*slab).free.push((slab as usize + self.first() + i * self.size) as *mut usize);
+        }
+        self.link(slab);
+        self.slabs += 1;
+        true
+    }
+
+    unsafe fn alloc(&mut self, heap: &impl GlobalAlloc) -> *mut u8 {
+        if self.partial.is_null() && !self.grow(heap) {
+            return ptr::null_mut();
+        }
+        let slab = self.partial;
+        let object = (*slab).free.pop().unwrap();
+        (*slab).in_use += 1;
+        if (*slab).free.is_empty() {
+            self.unlink(slab);
+        }
+        self.in_use += 1;
+        object as *mut u8
+    }
+
+    unsafe fn dealloc(&mut self, object: *mut u8, heap: &impl GlobalAlloc) {
+        let slab = (object as usize & !(self.slab_size - 1)) as *mut Slab;
+        let was_full = (*slab).free.is_empty();
+        (*slab).free.push(object as *mut usize);
+        (*slab).in_use -= 1;
+        self.in_use -= 1;
+        if was_full {
+            self.link(slab);
+        }
+        if (*slab).in_use == 0 && !((*slab).prev.is_null() && (*slab).next.is_null()) {
+            self.unlink(slab);
+            heap.dealloc(slab as *mut u8, self.slab_layout());
+            self.slabs -= 1;
+        }
+    }
+
+    fn stats(&self) -> SlabCacheStats {
+        SlabCacheStats {
+            name: self.name,
+            object_size: self.size,
+            objects_per_slab: self.objects_per_slab(),
+            slabs: self.slabs,
+            in_use: self.in_use,
+        }
+    }
+}
+
+/// Handle of a cache created by `SlabHeap::create_cache`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheId(usize);
+
+struct SlabCaches {
+    generic: [SlabCache; SIZE_CLASSES.len()],
+    named: [SlabCache; MAX_NAMED_CACHES],
+    named_count: usize,
+}
+
+/// The kernel's global allocator: slab caches for small objects in front of the buddy heap.
+pub struct SlabHeap<const ORDER: usize> {
+    caches: Mutex<SlabCaches>,
+    heap: LockedHeapWithRescue<ORDER>,
+}
+
+impl<const ORDER: usize> SlabHeap<ORDER> {
+    pub const fn new(rescue: fn(&mut Heap<ORDER>, &Layout)) -> Self {
+        let mut generic = [const { SlabCache::new("", 0, 1) }; SIZE_CLASSES.len()];
+        let mut i = 0;
+        while i < SIZE_CLASSES.len() {
+            let size = SIZE_CLASSES[i];
+            // The alignment an object of this size naturally gets: its lowest set bit.
+            generic[i] = SlabCache::new("kmalloc", size, size & (!size + 1));
+            i += 1;
+        }
+        SlabHeap {
+            caches: Mutex::new(SlabCaches {
+                generic,
+                named: [const { SlabCache::new("", 0, 1) }; MAX_NAMED_CACHES],
+                named_count: 0,
+            }),
+            heap: LockedHeapWithRescue::new(rescue),
+        }
+    }
+
+    /// The buddy heap behind the caches.
+    pub fn heap(&self) -> &LockedHeapWithRescue<ORDER> {
+        &self.heap
+    }
+
+    /// Creates a dedicated cache for objects of `layout`, e.g. a frequently allocated type.
+    pub fn create_cache(&self, name: &'static str, layout: Layout) -> Option<CacheId> {
+        let mut caches = self.caches.lock();
+        if caches.named_count == MAX_NAMED_CACHES {
+            return None;
+        }
+        let id = caches.named_count;
+        caches.named[id] = SlabCache::new(name, layout.size(), layout.align());
+        caches.named_count += 1;
+        Some(CacheId(id))
+    }
+
+    /// Allocates an object from the cache `id`. With the `debug-heap` feature the object
+    /// comes straight from the heap instead, so it gets red zones like any other allocation.
+    pub fn cache_alloc(&self, id: CacheId) -> Option<NonNull<u8>> {
+        let mut caches = self.caches.lock();
+        let cache = &mut caches.named[id.0];
+        if cfg!(feature = "debug-heap") {
+            let layout = cache.object_layout();
+            drop(caches);
+            return NonNull::new(unsafe { self.heap.alloc(layout) });
+        }
+        NonNull::new(unsafe { cache.alloc(&self.heap) })
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must come from `cache_alloc` with the same `id`.
+    pub unsafe fn cache_dealloc(&self, id: CacheId, ptr: NonNull<u8>) {
+        let mut caches = self.caches.lock();
+        let cache = &mut caches.named[id.0];
+        if cfg!(feature = "debug-heap") {
+            let layout = cache.object_layout();
+            drop(caches);
+            return self.heap.dealloc(ptr.as_ptr(), layout);
+        }
+        cache.dealloc(ptr.as_ptr(), &self.heap)
+    }
+
+    /// Statistics of the generic caches followed by the named ones.
+    pub fn stats(&self) -> [Option<SlabCacheStats>; MAX_SLAB_CACHES] {
+        let caches = self.caches.lock();
+        let mut stats = [None; MAX_SLAB_CACHES];
+        let named = &caches.named[..caches.named_count];
+        for (stat, cache) in stats.iter_mut().zip(caches.generic.iter().chain(named)) {
+            *stat = Some(cache.stats());
+        }
+        stats
+    }
+
+    fn class<'a>(caches: &'a mut SlabCaches, layout: &Layout) -> Option<&'a mut SlabCache> {
+        // Objects packed into slabs have no red zones, so the debug heap sees every allocation.
+        if cfg!(feature = "debug-heap") {
+            return None;
+        }
+        caches.generic.iter_mut().find(|cache| cache.fits(layout))
+    }
+}
+
+unsafe impl<const ORDER: usize> GlobalAlloc for SlabHeap<ORDER> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match Self::class(&mut self.caches.lock(), &layout) {
+            Some(cache) => cache.alloc(&self.heap),
+            None => self.heap.alloc(layout),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        match Self::class(&mut self.caches.lock(), &layout) {
+            Some(cache) => cache.dealloc(ptr, &self.heap),
+            None => self.heap.dealloc(ptr, layout),
+        }
+    }
+}
+
+/// A named slab cache holding values of type `T`, backed by the kernel heap.
+pub struct ObjectCache<T> {
+    id: CacheId,
+    phantom: PhantomData<T>,
+}
+
+impl<T> ObjectCache<T> {
+    pub fn new(name: &'static str) -> Option<Self> {
+        Some(ObjectCache {
+            id: super::create_cache(name, Layout::new::<T>())?,
+            phantom: PhantomData,
+        })
+    }
+
+    /// Moves `value` into a freshly allocated object.
+    pub fn alloc(&self, value: T) -> Option<NonNull<T>> {
+        let object = super::cache_alloc(self.id)?.cast::<T>();
+        unsafe { object.as_ptr().write(value) };
+        Some(object)
+    }
+
+    /// Drops the value in `object` and returns it to the cache.
+    ///
+    /// # Safety
+    ///
+    /// `object` must come from `alloc` on this cache and not be used afterwards.
+    pub unsafe fn free(&self, object: NonNull<T>) {
+        ptr::drop_in_place(object.as_ptr());
+        super::cache_dealloc(self.id, object.cast());
+    }
+}
@@ -0,0 +1,67 @@
+use crate::consts::PAGE_SIZE;
+use super::slab::{SlabCacheStats, MAX_SLAB_CACHES};
+use core::fmt;
+
+/// A snapshot of physical memory and kernel heap usage, see `memory::stats`.
+#[derive(Debug, Clone)]
+pub struct MemoryStats<const ORDER: usize> {
+    /// Frames spanned by RAM, holes included.
+    pub total_frames: usize,
+    /// Frames handed to the frame allocator at boot.
+    pub usable_frames: usize,
+    pub free_frames: usize,
+    pub heap_total_bytes: usize,
+    /// Bytes requested by heap users.
+    pub heap_user_bytes: usize,
+    /// Bytes handed out by the heap after rounding requests up to a power of two.
+    pub heap_allocated_bytes: usize,
+    /// Free heap blocks of `1 << class` bytes, indexed by class.
+    pub heap_free_blocks: [usize; ORDER],
+    /// Generic slab caches followed by the named ones.
+    pub slab_caches: [Option<SlabCacheStats>; MAX_SLAB_CACHES],
+}
+
+impl<const ORDER: usize> MemoryStats<ORDER> {
+    pub fn used_frames(&self) -> usize {
+        self.usable_frames - self.free_frames
+    }
+
+    pub fn reserved_frames(&self) -> usize {
+        self.total_frames - self.usable_frames
+    }
+
+    /// Bytes lost to rounding allocations up inside the heap.
+    pub fn heap_fragmentation_bytes(&self) -> usize {
+        self.heap_allocated_bytes - self.heap_user_bytes
+    }
+}
+
+impl<const ORDER: usize> fmt::Display for MemoryStats<ORDER> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kb = |frames: usize| frames * PAGE_SIZE / 1024;
+        writeln!(f, "MemTotal:       {:>10} kB", kb(self.total_frames))?;
+        writeln!(f, "MemReserved:    {:>10} kB", kb(self.reserved_frames()))?;
+        writeln!(f, "MemUsable:      {:>10} kB", kb(self.usable_frames))?;
+        writeln!(f, "MemUsed:        {:>10} kB", kb(self.used_frames()))?;
+        writeln!(f, "MemFree:        {:>10} kB", kb(self.free_frames))?;
+        writeln!(f, "HeapTotal:      {:>10} kB", self.heap_total_bytes / 1024)?;
+        writeln!(f, "HeapAllocated:  {:>10} B", self.heap_allocated_bytes)?;
+        writeln!(f, "HeapRequested:  {:>10} B", self.heap_user_bytes)?;
+        writeln!(f, "HeapFragmented: {:>10} B", self.heap_fragmentation_bytes())?;
+        writeln!(f, "HeapFreeBlocks:")?;
+        for (class, &count) in self.heap_free_blocks.iter().enumerate() {
+            if count != 0 {
+                writeln!(f, "  {:>10} B x {}", 1usize << class, count)?;
+            }
+        }
+        writeln!(f, "SlabCaches:       name  objsize  objs/slab    slabs   in use")?;
+        for cache in self.slab_caches.iter().flatten() {
+            writeln!(
+                f,
+                "  {:>20} {:>8} {:>10} {:>8} {:>8}",
+                cache.name, cache.object_size, cache.objects_per_slab, cache.slabs, cache.in_use
+            )?;
+        }
+        Ok(())
+    }
+}
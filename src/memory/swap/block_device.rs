@@ -0,0 +1,46 @@
+use alloc::vec;
+use alloc::vec::Vec;
+
+pub const BLOCK_SIZE: usize = 512;
+
+/// A device storing fixed size blocks, such as the swap area.
+pub trait BlockDevice: Send {
+    fn block_count(&self) -> usize;
+
+    /// Reads block `id` into `buf`, which is `BLOCK_SIZE` bytes long.
+    fn read_block(&mut self, id: usize, buf: &mut [u8]);
+
+    /// Writes `buf`, which is `BLOCK_SIZE` bytes long, to block `id`.
+    fn write_block(&mut self, id: usize, buf: &[u8]);
+}
+
+/// A block device kept in kernel heap memory.
+pub struct RamDisk {
+    data: Vec<u8>,
+}
+
+impl RamDisk {
+    pub fn new(block_count: usize) -> Self {
+        RamDisk {
+            data: vec![0; block_count * BLOCK_SIZE],
+        }
+    }
+
+    fn block(&mut self, id: usize) -> &mut [u8] {
+        &mut self.data[id * BLOCK_SIZE..(id + 1) * BLOCK_SIZE]
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_count(&self) -> usize {
+        self.data.len() / BLOCK_SIZE
+    }
+
+    fn read_block(&mut self, id: usize, buf: &mut [u8]) {
+        buf.copy_from_slice(self.block(id));
+    }
+
+    fn write_block(&mut self, id: usize, buf: &[u8]) {
+        self.block(id).copy_from_slice(buf);
+    }
+}
@@ -0,0 +1,230 @@
+//! Swapping pages out to a block device when physical memory runs low.
+//!
+//! Only pages of areas mapped by the `Swappable` handler take part. A swapped out page keeps
+//! its page table entry, with the valid bit cleared and the swap slot in place of the frame,
+//! so touching it faults and `swap_in` brings it back.
+
+pub mod block_device;
+pub mod policy;
+
+use block_device::{BlockDevice, BLOCK_SIZE};
+use policy::{ReferenceBits, SwapPolicy};
+use crate::address::*;
+use crate::consts::PAGE_SIZE;
+use crate::page_table::MapToError;
+use crate::paging::{access_pa_via_va, PageEntry};
+use crate::utils::mutex::Mutex;
+use super::{alloc_frame, dealloc_frame};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::fmt;
+
+const BLOCKS_PER_SLOT: usize = PAGE_SIZE / BLOCK_SIZE;
+
+impl ReferenceBits for PageEntry {
+    fn accessed(&self) -> bool {
+        PageEntry::accessed(self)
+    }
+
+    fn clear_accessed(&mut self) {
+        PageEntry::clear_accessed(self);
+        // Otherwise the hardware would not set the bit again on the next access.
+        self.update_all_asids();
+    }
+
+    fn dirty(&self) -> bool {
+        PageEntry::dirty(self)
+    }
+}
+
+/// Counters of the swap manager, see `swap::stats`.
+#[derive(Debug, Clone, Copy)]
+pub struct SwapStats {
+    pub policy: &'static str,
+    /// Pages taking part in swapping that currently have a frame.
+    pub resident: usize,
+    pub used_slots: usize,
+    pub total_slots: usize,
+    pub page_ins: usize,
+    pub page_outs: usize,
+}
+
+impl fmt::Display for SwapStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "swap ({}): {} pages resident, {}/{} slots used, {} page-ins, {} page-outs",
+            self.policy, self.resident, self.used_slots, self.total_slots, self.page_ins, self.page_outs
+        )
+    }
+}
+
+/// A swap area on a block device and the resident pages that may be swapped out to it.
+pub struct SwapManager {
+    device: Box<dyn BlockDevice>,
+    policy: Box<dyn SwapPolicy<PageEntry>>,
+    free_slots: Vec<usize>,
+    total_slots: usize,
+    max_resident: usize,
+    page_ins: usize,
+    page_outs: usize,
+}
+
+impl SwapManager {
+    /// Swaps to the whole of `device`, picking victims with `policy`. If `max_resident` is
+    /// not zero, the pages taking part never hold more frames than that at once; otherwise
+    /// they are only swapped out when `alloc_frame` runs out of frames.
+    pub fn new(
+        device: impl BlockDevice + 'static,
+        policy: impl SwapPolicy<PageEntry> + 'static,
+        max_resident: usize,
+    ) -> Self {
+        let total_slots = device.block_count() / BLOCKS_PER_SLOT;
+        SwapManager {
+            device: Box::new(device),
+            policy: Box::new(policy),
+            free_slots: (0..total_slots).rev().collect(),
+            total_slots,
+            max_resident,
+            page_ins: 0,
+            page_outs: 0,
+        }
+    }
+
+    fn write_slot(&mut self, slot: usize, pa: usize) {
+        for i in 0..BLOCKS_PER_SLOT {
+            let buf = unsafe {
+                core::slice::from_raw_parts((access_pa_via_va(pa) + i * BLOCK_SIZE) as *const u8, BLOCK_SIZE)
+            };
+            self.device.write_block(slot * BLOCKS_PER_SLOT + i, buf);
+        }
+    }
+
+    fn read_slot(&mut self, slot: usize, pa: usize) {
+        for i in 0..BLOCKS_PER_SLOT {
+            let buf = unsafe {
+                core::slice::from_raw_parts_mut((access_pa_via_va(pa) + i * BLOCK_SIZE) as *mut u8, BLOCK_SIZE)
+            };
+            self.device.read_block(slot * BLOCKS_PER_SLOT + i, buf);
+        }
+    }
+
+    /// Writes the victim of the policy to a free slot and frees its frame. Returns whether a
+    /// page could be swapped out.
+    fn swap_out(&mut self) -> bool {
+        if self.free_slots.is_empty() {
+            return false;
+        }
+        let Some(mut victim) = self.policy.victim() else {
+            return false;
+        };
+        let slot = self.free_slots.pop().unwrap();
+        let pa = victim.target();
+        self.write_slot(slot, pa);
+        victim.set_swapped_out(slot);
+        victim.update_all_asids();
+        dealloc_frame(Frame::of_addr(PhysAddr::new(pa)));
+        self.page_outs += 1;
+        true
+    }
+
+    fn insert(&mut self, entry: PageEntry) {
+        while self.max_resident != 0 && self.policy.len() >= self.max_resident && self.swap_out() {}
+        self.policy.push(entry);
+    }
+
+    fn stats(&self) -> SwapStats {
+        SwapStats {
+            policy: self.policy.name(),
+            resident: self.policy.len(),
+            used_slots: self.total_slots - self.free_slots.len(),
+            total_slots: self.total_slots,
+            page_ins: self.page_ins,
+            page_outs: self.page_outs,
+        }
+    }
+}
+
+pub static SWAP_MANAGER: Mutex<Option<SwapManager>> = Mutex::new(None);
+
+/// Swaps with `manager` from now on. No page may be taking part in swapping with the
+/// previous manager any more.
+pub fn init(manager: SwapManager) {
+    let mut current = SWAP_MANAGER.lock();
+    if let Some(stats) = current.as_ref().map(SwapManager::stats) {
+        assert!(
+            stats.resident == 0 && stats.used_slots == 0,
+            "replacing the swap manager while it still has pages"
+        );
+    }
+    *current = Some(manager);
+}
+
+/// Swaps a page out to make room, for `alloc_frame` once it runs out of frames.
+pub fn swap_out() -> bool {
+    // Already locked means the frame is wanted by the swap manager itself.
+    SWAP_MANAGER
+        .try_lock()
+        .is_some_and(|mut manager| manager.as_mut().is_some_and(SwapManager::swap_out))
+}
+
+/// Lets the swap manager swap out the page behind `entry`, which has just been mapped. Other
+/// pages may be swapped out to stay within the resident limit.
+///
+/// # Safety
+///
+/// The page table must outlive the page, and the page must be passed to `remove` or
+/// `free_slot` before it is unmapped.
+pub unsafe fn insert(entry: &mut PageEntry) {
+    if let Some(manager) = SWAP_MANAGER.lock().as_mut() {
+        manager.insert(entry.detach());
+    }
+}
+
+/// Stops swapping out the resident page behind `entry`.
+pub fn remove(entry: &PageEntry) {
+    if let Some(manager) = SWAP_MANAGER.lock().as_mut() {
+        manager.policy.remove(&|page| page.is_same(entry));
+    }
+}
+
+/// Frees the slot of the swapped out page behind `entry` and clears the entry.
+pub fn free_slot(entry: &mut PageEntry) {
+    let mut manager = SWAP_MANAGER.lock();
+    let manager = manager.as_mut().expect("page swapped out without a swap manager");
+    manager.free_slots.push(entry.swap_slot());
+    entry.clear();
+    entry.update();
+}
+
+/// Copies the swapped out page behind `entry` into the frame at `pa`, leaving it swapped out.
+pub fn read_swapped(entry: &PageEntry, pa: usize) {
+    let mut manager = SWAP_MANAGER.lock();
+    let manager = manager.as_mut().expect("page swapped out without a swap manager");
+    manager.read_slot(entry.swap_slot(), pa);
+}
+
+/// Reads the swapped out page behind `entry` back into a new frame.
+///
+/// # Safety
+///
+/// Same as `insert`.
+pub unsafe fn swap_in(entry: &mut PageEntry) -> Result<(), MapToError> {
+    // May swap out another page, so it has to happen before the manager is locked.
+    let frame = alloc_frame().ok_or(MapToError::FrameAllocationFailed)?;
+    let pa = frame.start_address().as_usize();
+    let mut manager = SWAP_MANAGER.lock();
+    let manager = manager.as_mut().expect("page swapped out without a swap manager");
+    let slot = entry.swap_slot();
+    manager.read_slot(slot, pa);
+    manager.free_slots.push(slot);
+    entry.set_swapped_in(pa);
+    entry.update();
+    manager.page_ins += 1;
+    manager.insert(entry.detach());
+    Ok(())
+}
+
+pub fn stats() -> Option<SwapStats> {
+    SWAP_MANAGER.lock().as_ref().map(SwapManager::stats)
+}
@@ -0,0 +1,299 @@
+//! Page replacement policies: which resident page to swap out next.
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+/// The referenced and modified bits the hardware keeps for a resident page.
+pub trait ReferenceBits {
+    fn accessed(&self) -> bool;
+    fn clear_accessed(&mut self);
+    fn dirty(&self) -> bool;
+}
+
+/// Keeps track of the resident pages and picks victims among them.
+pub trait SwapPolicy<P>: Send {
+    fn name(&self) -> &'static str;
+
+    /// Adds a page that just became resident.
+    fn push(&mut self, page: P);
+
+    /// Forgets the first page matching `pred`, e.g. because it was unmapped.
+    fn remove(&mut self, pred: &dyn Fn(&P) -> bool) -> Option<P>;
+
+    /// Picks the page to swap out and forgets it.
+    fn victim(&mut self) -> Option<P>;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Swaps out the page that became resident first.
+pub struct Fifo<P> {
+    pages: VecDeque<P>,
+}
+
+impl<P> Fifo<P> {
+    pub fn new() -> Self {
+        Fifo { pages: VecDeque::new() }
+    }
+}
+
+impl<P> Default for Fifo<P> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<P: ReferenceBits + Send> SwapPolicy<P> for Fifo<P> {
+    fn name(&self) -> &'static str {
+        "fifo"
+    }
+
+    fn push(&mut self, page: P) {
+        self.pages.push_back(page);
+    }
+
+    fn remove(&mut self, pred: &dyn Fn(&P) -> bool) -> Option<P> {
+        let i = self.pages.iter().position(pred)?;
+        self.pages.remove(i)
+    }
+
+    fn victim(&mut self) -> Option<P> {
+        self.pages.pop_front()
+    }
+
+    fn len(&self) -> usize {
+        self.pages.len()
+    }
+}
+
+/// Resident pages in a circle with a hand pointing at the next one to look at. New pages
+/// go right behind the hand, so they are looked at last.
+struct Ring<P> {
+    pages: Vec<P>,
+    hand: usize,
+}
+
+impl<P> Ring<P> {
+    fn new() -> Self {
+        Ring {
+            pages: Vec::new(),
+            hand: 0,
+        }
+    }
+
+    fn push(&mut self, page: P) {
+        self.hand = self.hand.min(self.pages.len());
+        self.pages.insert(self.hand, page);
+        self.hand += 1;
+    }
+
+    fn remove(&mut self, pred: &dyn Fn(&P) -> bool) -> Option<P> {
+        let i = self.pages.iter().position(pred)?;
+        if i < self.hand {
+            self.hand -= 1;
+        }
+        Some(self.pages.remove(i))
+    }
+
+    /// The index under the hand, then advances it.
+    fn advance(&mut self) -> usize {
+        let i = self.hand % self.pages.len();
+        self.hand = i + 1;
+        i
+    }
+
+    /// Removes the page at `i`, which the hand just passed; the hand stays on its successor.
+    fn take(&mut self, i: usize) -> P {
+        self.hand = i;
+        self.pages.remove(i)
+    }
+}
+
+/// Second chance: a page that was accessed since the hand last passed it has its accessed
+/// bit cleared and is skipped.
+pub struct Clock<P> {
+    ring: Ring<P>,
+}
+
+impl<P> Clock<P> {
+    pub fn new() -> Self {
+        Clock { ring: Ring::new() }
+    }
+}
+
+impl<P> Default for Clock<P> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<P: ReferenceBits + Send> SwapPolicy<P> for Clock<P> {
+    fn name(&self) -> &'static str {
+        "clock"
+    }
+
+    fn push(&mut self, page: P) {
+        self.ring.push(page);
+    }
+
+    fn remove(&mut self, pred: &dyn Fn(&P) -> bool) -> Option<P> {
+        self.ring.remove(pred)
+    }
+
+    fn victim(&mut self) -> Option<P> {
+        if self.ring.pages.is_empty() {
+            return None;
+        }
+        loop {
+            let i = self.ring.advance();
+            let page = &mut self.ring.pages[i];
+            if !page.accessed() {
+                return Some(self.ring.take(i));
+            }
+            page.clear_accessed();
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.ring.pages.len()
+    }
+}
+
+/// Like `Clock`, but prefers clean pages, those not written since they were mapped or
+/// swapped in. Each round first looks for a page neither accessed nor dirty without touching
+/// anything, then for one not accessed but dirty while clearing the accessed bits.
+pub struct EnhancedClock<P> {
+    ring: Ring<P>,
+}
+
+impl<P> EnhancedClock<P> {
+    pub fn new() -> Self {
+        EnhancedClock { ring: Ring::new() }
+    }
+}
+
+impl<P> Default for EnhancedClock<P> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<P: ReferenceBits + Send> SwapPolicy<P> for EnhancedClock<P> {
+    fn name(&self) -> &'static str {
+        "enhanced clock"
+    }
+
+    fn push(&mut self, page: P) {
+        self.ring.push(page);
+    }
+
+    fn remove(&mut self, pred: &dyn Fn(&P) -> bool) -> Option<P> {
+        self.ring.remove(pred)
+    }
+
+    fn victim(&mut self) -> Option<P> {
+        if self.ring.pages.is_empty() {
+            return None;
+        }
+        // The second pass clears every accessed bit, so the next round finds a victim.
+        loop {
+            for dirty in [false, true] {
+                for _ in 0..self.ring.pages.len() {
+                    let i = self.ring.advance();
+                    let page = &mut self.ring.pages[i];
+                    if !page.accessed() && page.dirty() == dirty {
+                        return Some(self.ring.take(i));
+                    }
+                    if dirty {
+                        page.clear_accessed();
+                    }
+                }
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.ring.pages.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct Page {
+        id: usize,
+        accessed: bool,
+        dirty: bool,
+    }
+
+    impl ReferenceBits for Page {
+        fn accessed(&self) -> bool {
+            self.accessed
+        }
+        fn clear_accessed(&mut self) {
+            self.accessed = false;
+        }
+        fn dirty(&self) -> bool {
+            self.dirty
+        }
+    }
+
+    /// Pushes pages `1..` with the given accessed and dirty bits, then takes every victim.
+    fn victims(mut policy: impl SwapPolicy<Page>, bits: &[(bool, bool)]) -> Vec<usize> {
+        for (i, &(accessed, dirty)) in bits.iter().enumerate() {
+            policy.push(Page { id: i + 1, accessed, dirty });
+        }
+        let victims = core::iter::from_fn(|| policy.victim()).map(|page| page.id).collect();
+        assert!(policy.is_empty());
+        victims
+    }
+
+    #[test]
+    fn fifo_ignores_reference_bits() {
+        let bits = [(true, true), (false, false), (true, false)];
+        assert_eq!(victims(Fifo::new(), &bits), [1, 2, 3]);
+
+        let mut fifo = Fifo::new();
+        for id in 1..4 {
+            fifo.push(Page { id, accessed: false, dirty: false });
+        }
+        assert_eq!(fifo.remove(&|page| page.id == 2).map(|page| page.id), Some(2));
+        assert_eq!(fifo.victim().map(|page| page.id), Some(1));
+        assert_eq!(fifo.victim().map(|page| page.id), Some(3));
+    }
+
+    #[test]
+    fn clock_gives_accessed_pages_a_second_chance() {
+        let bits = [(true, false), (false, false), (true, false)];
+        assert_eq!(victims(Clock::new(), &bits), [2, 1, 3]);
+        // Dirty pages are not treated specially.
+        assert_eq!(victims(Clock::new(), &[(false, true), (false, false)]), [1, 2]);
+    }
+
+    #[test]
+    fn enhanced_clock_prefers_clean_pages() {
+        assert_eq!(victims(EnhancedClock::new(), &[(false, true), (false, false)]), [2, 1]);
+        let bits = [(true, true), (false, true), (true, false)];
+        assert_eq!(victims(EnhancedClock::new(), &bits), [2, 1, 3]);
+    }
+
+    #[test]
+    fn new_pages_go_behind_the_hand() {
+        let mut clock = Clock::new();
+        for id in 1..4 {
+            clock.push(Page { id, accessed: true, dirty: false });
+        }
+        // Clears every accessed bit and takes page 1, leaving the hand on page 2.
+        assert_eq!(clock.victim().map(|page| page.id), Some(1));
+        clock.push(Page { id: 4, accessed: false, dirty: false });
+        assert_eq!(clock.remove(&|page| page.id == 3).map(|page| page.id), Some(3));
+        assert_eq!(clock.victim().map(|page| page.id), Some(2));
+        assert_eq!(clock.victim().map(|page| page.id), Some(4));
+    }
+}
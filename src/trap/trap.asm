@@ -12,6 +12,29 @@
 	bnez sp, trap_from_user
 trap_from_kernel:
 	csrr sp, sscratch
+	# 若 sp 已经接近或落入某个内核栈下方的保护页，TrapFrame 放不下，内核栈已经溢出
+	# 此时 sscratch 与 sp 相同，借它暂存 t0 来做判断
+	csrw sscratch, t0
+	# t0 := sp - 1 - 内核栈区域起始地址，不在区域内则照常处理
+	li t0, {KERNEL_STACK_BASE} + 1
+	sub t0, sp, t0
+	srli t0, t0, {KERNEL_STACK_REGION_SHIFT}
+	bnez t0, trap_on_current_stack
+	# t0 := (sp - 1) 在所在槽内的偏移，除以 32 后与保护页加 TrapFrame 的大小比较
+	li t0, {KERNEL_STACK_BASE} + 1
+	sub t0, sp, t0
+	slli t0, t0, 64 - {KERNEL_STACK_SLOT_SHIFT}
+	srli t0, t0, 64 - {KERNEL_STACK_SLOT_SHIFT} + 5
+	sltiu t0, t0, (4096 + 36*XLENB) / 32
+	beqz t0, trap_on_current_stack
+	# 内核栈溢出：换到专门的栈上，由 rust_trap 报告
+	csrr t0, sscratch
+	csrw sscratch, sp
+	la sp, stack_overflow_stack_top
+	j trap_from_user
+trap_on_current_stack:
+	csrr t0, sscratch
+	csrw sscratch, sp
 trap_from_user:
 	addi sp, sp, -36*XLENB
 	STORE x1, 1
@@ -113,4 +136,11 @@ __alltraps:
 	.globl __trapret
 __trapret:
 	RESTORE_ALL
-	sret
\ No newline at end of file
+	sret
+
+	.section .bss
+	.align 12
+	# 内核栈溢出后处理陷入所用的栈
+stack_overflow_stack:
+	.space 4096 * 4
+stack_overflow_stack_top:
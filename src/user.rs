@@ -0,0 +1,207 @@
+//! Kernel access to user memory.
+//!
+//! Every access is checked against the user areas of the current address space first, and
+//! runs with `sstatus.SUM` set. The copies themselves are done by the routines in
+//! `user_access.asm`: a fault in them that the page fault handler cannot resolve makes the
+//! copy return `Efault` instead of panicking.
+
+use core::arch::global_asm;
+use core::marker::PhantomData;
+use core::mem::{size_of, MaybeUninit};
+use alloc::vec;
+use alloc::vec::Vec;
+use crate::consts::PAGE_SIZE;
+use crate::memory::memory_set::with_current;
+use crate::register::sstatus;
+
+global_asm!(include_str!("user/user_access.asm"));
+
+extern "C" {
+    fn __copy_user(dst: *mut u8, src: *const u8, len: usize) -> isize;
+    fn __strncpy_user(dst: *mut u8, src: *const u8, max: usize) -> isize;
+    fn __user_access_start();
+    fn __user_access_end();
+    fn __user_access_fixup();
+}
+
+/// A bad user address: outside user memory, not writable when written to, or faulting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Efault;
+
+/// Where the page fault handler resumes a user access it cannot resolve, if `sepc` is in
+/// one of the user access routines and the fault was on the user side of the copy, at `va`
+/// in a user area. A fault on the kernel buffer is a kernel bug and is not covered up.
+pub fn fixup(sepc: usize, va: usize) -> Option<usize> {
+    let start = __user_access_start as *const () as usize;
+    let end = __user_access_end as *const () as usize;
+    if !(start..end).contains(&sepc) {
+        return None;
+    }
+    let user = with_current(|memory_set| memory_set.find_area(va).is_some_and(|area| area.attr().user()));
+    user.unwrap_or(false).then_some(__user_access_fixup as *const () as usize)
+}
+
+/// Checks that `[addr, addr + len)` lies in user areas of the active address space, which
+/// are writable if `write` is set.
+fn check(addr: usize, len: usize, write: bool) -> Result<(), Efault> {
+    let end = addr.checked_add(len).ok_or(Efault)?;
+    // The address space is unlocked again before the access, which may fault.
+    with_current(|memory_set| {
+        let mut covered = addr;
+        while covered < end {
+            let area = memory_set.find_area(covered).ok_or(Efault)?;
+            if !area.attr().user() || (write && area.attr().readonly()) {
+                return Err(Efault);
+            }
+            covered = area.end();
+        }
+        Ok(())
+    })
+    .map_err(|_| Efault)?
+}
+
+/// Sets `sstatus.SUM` while alive and restores it afterwards.
+struct SumGuard(bool);
+
+impl SumGuard {
+    fn new() -> Self {
+        let sum = sstatus::read().sum();
+        unsafe { sstatus::set_sum() };
+        SumGuard(sum)
+    }
+}
+
+impl Drop for SumGuard {
+    fn drop(&mut self) {
+        if !self.0 {
+            unsafe { sstatus::clear_sum() };
+        }
+    }
+}
+
+/// Copies `dst.len()` bytes from user address `src`.
+pub fn copy_from_user(dst: &mut [u8], src: usize) -> Result<(), Efault> {
+    check(src, dst.len(), false)?;
+    let _sum = SumGuard::new();
+    match unsafe { __copy_user(dst.as_mut_ptr(), src as *const u8, dst.len()) } {
+        0 => Ok(()),
+        _ => Err(Efault),
+    }
+}
+
+/// Copies `src` to user address `dst`.
+pub fn copy_to_user(dst: usize, src: &[u8]) -> Result<(), Efault> {
+    check(dst, src.len(), true)?;
+    let _sum = SumGuard::new();
+    match unsafe { __copy_user(dst as *mut u8, src.as_ptr(), src.len()) } {
+        0 => Ok(()),
+        _ => Err(Efault),
+    }
+}
+
+/// Copies the NUL-terminated string at user address `src` into `dst`, including the NUL,
+/// and returns its length. If `dst` fills up first, the string is cut off there and its
+/// length is `dst.len()`.
+///
+/// Only the part of the string up to the NUL needs to be mapped, so the range is checked
+/// page by page along with the copy.
+pub fn strncpy_from_user(dst: &mut [u8], src: usize) -> Result<usize, Efault> {
+    let mut copied = 0;
+    while copied < dst.len() {
+        let addr = src.checked_add(copied).ok_or(Efault)?;
+        let chunk = (PAGE_SIZE - addr % PAGE_SIZE).min(dst.len() - copied);
+        check(addr, chunk, false)?;
+        let len = {
+            let _sum = SumGuard::new();
+            unsafe { __strncpy_user(dst[copied..].as_mut_ptr(), addr as *const u8, chunk) }
+        };
+        if len < 0 {
+            return Err(Efault);
+        }
+        copied += len as usize;
+        if (len as usize) < chunk {
+            return Ok(copied);
+        }
+    }
+    Ok(copied)
+}
+
+/// Plain data that may be read from user memory: every bit pattern is a valid value.
+///
+/// # Safety
+///
+/// The type must have no invalid bit patterns, padding or pointers, unlike `bool`, `char`
+/// or most enums.
+pub unsafe trait Pod: Copy {}
+
+macro_rules! impl_pod {
+    ($($t:ty),*) => { $(unsafe impl Pod for $t {})* };
+}
+
+impl_pod!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
+
+/// A pointer to a `T` in user memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UserPtr<T> {
+    addr: usize,
+    phantom: PhantomData<T>,
+}
+
+impl<T: Pod> UserPtr<T> {
+    pub fn new(addr: usize) -> Self {
+        UserPtr {
+            addr,
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn read(&self) -> Result<T, Efault> {
+        let mut value = MaybeUninit::<T>::uninit();
+        let bytes = unsafe { core::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, size_of::<T>()) };
+        copy_from_user(bytes, self.addr)?;
+        Ok(unsafe { value.assume_init() })
+    }
+
+    pub fn write(&self, value: T) -> Result<(), Efault> {
+        let bytes = unsafe { core::slice::from_raw_parts(&value as *const T as *const u8, size_of::<T>()) };
+        copy_to_user(self.addr, bytes)
+    }
+}
+
+/// A byte buffer in user memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UserSlice {
+    addr: usize,
+    len: usize,
+}
+
+impl UserSlice {
+    pub fn new(addr: usize, len: usize) -> Self {
+        UserSlice { addr, len }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Copies the whole buffer into kernel memory.
+    pub fn read(&self) -> Result<Vec<u8>, Efault> {
+        let mut buf = vec![0; self.len];
+        copy_from_user(&mut buf, self.addr)?;
+        Ok(buf)
+    }
+
+    /// Copies `data` to the start of the buffer, which must be large enough.
+    pub fn write(&self, data: &[u8]) -> Result<(), Efault> {
+        if data.len() > self.len {
+            return Err(Efault);
+        }
+        copy_to_user(self.addr, data)
+    }
+}
@@ -0,0 +1,48 @@
+# 访问用户内存的函数。它们中的指令访问用户内存出错且缺页无法处理时，
+# 异常处理程序将 sepc 改为 __user_access_fixup，使函数返回 -1
+
+	.section .text
+	.globl __user_access_start
+__user_access_start:
+
+	.globl __copy_user
+# a0 = 目标地址, a1 = 源地址, a2 = 字节数
+# 成功返回 0
+__copy_user:
+	beqz a2, 2f
+1:
+	lb t0, 0(a1)
+	sb t0, 0(a0)
+	addi a0, a0, 1
+	addi a1, a1, 1
+	addi a2, a2, -1
+	bnez a2, 1b
+2:
+	li a0, 0
+	ret
+
+	.globl __strncpy_user
+# a0 = 目标地址, a1 = 源地址, a2 = 最多复制的字节数
+# 返回不含结尾 0 的字符串长度，复制了 a2 个字节仍未遇到 0 则返回 a2
+__strncpy_user:
+	li t1, 0
+1:
+	beq t1, a2, 2f
+	lb t0, 0(a1)
+	sb t0, 0(a0)
+	beqz t0, 2f
+	addi a0, a0, 1
+	addi a1, a1, 1
+	addi t1, t1, 1
+	j 1b
+2:
+	mv a0, t1
+	ret
+
+	.globl __user_access_end
+__user_access_end:
+
+	.globl __user_access_fixup
+__user_access_fixup:
+	li a0, -1
+	ret
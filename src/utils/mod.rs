@@ -1,3 +1,4 @@
 #[macro_use]
 pub mod spinlock;
 pub mod mutex;
+pub mod volatile;
@@ -0,0 +1,70 @@
+//! Typed access to memory-mapped device registers.
+//!
+//! Drivers describe a block of registers as a `#[repr(C)]` struct of these wrappers and
+//! place it over the address `ioremap` returned. Every access is a single volatile load or
+//! store of the whole register.
+
+use core::cell::UnsafeCell;
+
+/// A register that can be read and written.
+#[repr(transparent)]
+pub struct Volatile<T: Copy>(UnsafeCell<T>);
+
+impl<T: Copy> Volatile<T> {
+    pub fn read(&self) -> T {
+        unsafe { self.0.get().read_volatile() }
+    }
+
+    pub fn write(&self, value: T) {
+        unsafe { self.0.get().write_volatile(value) }
+    }
+
+    /// Reads the register, then writes back what `f` makes of the value.
+    pub fn modify(&self, f: impl FnOnce(T) -> T) {
+        self.write(f(self.read()));
+    }
+}
+
+/// A register that must not be written.
+#[repr(transparent)]
+pub struct ReadOnly<T: Copy>(Volatile<T>);
+
+impl<T: Copy> ReadOnly<T> {
+    pub fn read(&self) -> T {
+        self.0.read()
+    }
+}
+
+/// A register that must not be read, e.g. because reading it has side effects.
+#[repr(transparent)]
+pub struct WriteOnly<T: Copy>(Volatile<T>);
+
+impl<T: Copy> WriteOnly<T> {
+    pub fn write(&self, value: T) {
+        self.0.write(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[repr(C)]
+    struct Registers {
+        control: Volatile<u32>,
+        status: ReadOnly<u32>,
+        data: WriteOnly<u32>,
+    }
+
+    #[test]
+    fn registers_are_laid_out_like_their_values() {
+        let mut words = [0u32, 0x80, 0];
+        let registers = unsafe { &*(words.as_mut_ptr() as *const Registers) };
+        registers.control.write(5);
+        registers.control.modify(|value| value | 2);
+        assert_eq!(registers.control.read(), 7);
+        assert_eq!(registers.status.read(), 0x80);
+        registers.data.write(0x1234);
+        assert_eq!(words, [7, 0x80, 0x1234]);
+    }
+}